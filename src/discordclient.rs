@@ -1,257 +1,1626 @@
+use crate::discorderror::*;
+use crate::discordetf;
+use crate::discordhttp::*;
 use crate::discordmessage::*;
-use async_native_tls::TlsStream;
-use async_std::net::TcpStream;
+use crate::discordratelimit::{CommandKind, CommandLimiter};
+use crate::discordrecord::Recorder;
+use crate::discordtransport::*;
 use futures_util::sink::SinkExt;
-use httparse::Header;
+use serde::Deserialize;
 use serde_json::json;
-use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
 use tokio::select;
 use tokio::stream::StreamExt;
+use tokio::sync::{mpsc, oneshot};
 use tungstenite::Message;
 
+/// Session starts kept in hand. Once the day's remaining starts drop to this,
+/// identifying waits for the daily reset rather than risk a token reset.
+pub const SESSION_START_RESERVE: u32 = 10;
+/// Every zlib-stream payload ends with a Z_SYNC_FLUSH marker.
+const ZLIB_SUFFIX: &[u8] = &[0x00, 0x00, 0xff, 0xff];
+/// First byte of a zlib stream (deflate, 32K window). ETF payloads start with
+/// their version byte, 131, instead.
+const ZLIB_HEADER: u8 = 0x78;
+
+/// Wire encoding of gateway payloads, chosen when the connection is opened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Json,
+    Etf,
+}
+
+impl Encoding {
+    pub fn query(self) -> &'static str {
+        match self {
+            Encoding::Json => "json",
+            Encoding::Etf => "etf",
+        }
+    }
+    fn encode(self, payload: &serde_json::Value) -> Message {
+        match self {
+            Encoding::Json => Message::text(payload.to_string()),
+            Encoding::Etf => Message::binary(discordetf::encode(payload)),
+        }
+    }
+    pub fn decode(self, data: &[u8]) -> DiscordResult<DiscordMessage> {
+        match self {
+            Encoding::Json => serde_json::from_slice(data).map_err(|e| {
+                let text = String::from_utf8_lossy(data);
+                let rest = text.get(e.column()..).unwrap_or("");
+                DiscordError::Decode(format!("{}\nMsg: {}\n>>> {}", e, text, rest))
+            }),
+            Encoding::Etf => {
+                let value = discordetf::decode(data).map_err(DiscordError::Decode)?;
+                DiscordMessage::deserialize(&value)
+                    .map_err(|e| DiscordError::Decode(format!("{}\nMsg: {}", e, value)))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct HealthState {
+    heartbeat_sent: Option<tokio::time::Instant>,
+    latency: Option<std::time::Duration>,
+    last_dispatch: Option<tokio::time::Instant>,
+    reconnects: u64,
+    resumes: u64,
+    invalid_sessions: u64,
+}
+
+/// A snapshot of a gateway session's health.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GatewayStats {
+    /// Round trip of the last acknowledged heartbeat.
+    pub latency: Option<std::time::Duration>,
+    pub since_last_dispatch: Option<std::time::Duration>,
+    pub reconnects: u64,
+    pub resumes: u64,
+    pub invalid_sessions: u64,
+}
+
+/// Connection health of a gateway session, kept up to date as it runs so it
+/// can be read from other tasks. Clones share the same numbers.
+#[derive(Debug, Clone, Default)]
+pub struct GatewayHealth {
+    state: Arc<Mutex<HealthState>>,
+}
+
+impl GatewayHealth {
+    pub fn stats(&self) -> GatewayStats {
+        let state = self.state.lock().unwrap();
+        GatewayStats {
+            latency: state.latency,
+            since_last_dispatch: state.last_dispatch.map(|at| at.elapsed()),
+            reconnects: state.reconnects,
+            resumes: state.resumes,
+            invalid_sessions: state.invalid_sessions,
+        }
+    }
+    fn update(&self, f: impl FnOnce(&mut HealthState)) {
+        f(&mut self.state.lock().unwrap())
+    }
+    fn heartbeat_sent(&self) {
+        self.update(|s| s.heartbeat_sent = Some(tokio::time::Instant::now()));
+    }
+    /// Times a heartbeat sent on Discord's request (op 1), unless one is
+    /// already in flight: the ACK that follows can't be told apart from the
+    /// earlier heartbeat's.
+    fn heartbeat_requested(&self) {
+        self.update(|s| {
+            s.heartbeat_sent
+                .get_or_insert_with(tokio::time::Instant::now);
+        });
+    }
+    fn heartbeat_acked(&self) {
+        self.update(|s| {
+            if let Some(sent) = s.heartbeat_sent.take() {
+                s.latency = Some(sent.elapsed());
+            }
+        });
+    }
+}
+
+/// State shared between a gateway connection and its heartbeat task. The task
+/// only holds a weak reference, so it stops once the connection is dropped.
+struct Heartbeat {
+    encoding: Encoding,
+    /// Frames sent straight away, ahead of any queued commands.
+    outbox: mpsc::UnboundedSender<Message>,
+    zombie: mpsc::UnboundedSender<()>,
+    acked: AtomicBool,
+    health: GatewayHealth,
+}
+
+/// Inflates binary gateway frames. With `compress=zlib-stream` the whole
+/// connection is one zlib stream, and a payload may be split over several
+/// frames, so input is buffered until the sync flush suffix arrives. Without
+/// it, binary frames are either standalone zlib payloads (identify
+/// `compress`) or plain ETF, told apart by the zlib header byte.
+struct Inflater {
+    stream: Option<flate2::Decompress>,
+    buf: Vec<u8>,
+}
+
+impl Inflater {
+    fn new(zlib_stream: bool) -> Inflater {
+        Inflater {
+            stream: if zlib_stream {
+                Some(flate2::Decompress::new(true))
+            } else {
+                None
+            },
+            buf: vec![],
+        }
+    }
+    /// Returns the decompressed payload, or `None` if more frames are needed.
+    fn push(&mut self, data: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        let out = match &mut self.stream {
+            Some(stream) => {
+                self.buf.extend_from_slice(data);
+                if !self.buf.ends_with(ZLIB_SUFFIX) {
+                    return Ok(None);
+                }
+                let out = inflate(stream, &self.buf);
+                self.buf.clear();
+                out
+            }
+            None if data.first() == Some(&ZLIB_HEADER) => {
+                inflate(&mut flate2::Decompress::new(true), data)
+            }
+            None => Ok(data.to_vec()),
+        };
+        out.map(Some)
+    }
+}
+
+fn inflate(stream: &mut flate2::Decompress, input: &[u8]) -> Result<Vec<u8>, String> {
+    let mut out = Vec::with_capacity(input.len() * 4);
+    let mut consumed = 0;
+    loop {
+        if out.len() == out.capacity() {
+            out.reserve(std::cmp::max(out.capacity(), 1024));
+        }
+        let (before_in, before_out) = (stream.total_in(), stream.total_out());
+        let status = stream
+            .decompress_vec(&input[consumed..], &mut out, flate2::FlushDecompress::Sync)
+            .map_err(|e| e.to_string())?;
+        consumed += (stream.total_in() - before_in) as usize;
+        // output space left over means the inflater has nothing more to give
+        if consumed == input.len()
+            && (out.len() < out.capacity() || status == flate2::Status::StreamEnd)
+        {
+            return Ok(out);
+        }
+        if status == flate2::Status::StreamEnd {
+            return Err(format!(
+                "{} bytes left over after the end of the zlib stream",
+                input.len() - consumed
+            ));
+        }
+        if stream.total_in() == before_in && stream.total_out() == before_out {
+            return Err(format!(
+                "zlib stream stalled with {} bytes left",
+                input.len() - consumed
+            ));
+        }
+    }
+}
+
+struct Connection {
+    stream: MessageStream,
+    inflater: Inflater,
+    heartbeat: Arc<Heartbeat>,
+    zombie: mpsc::UnboundedReceiver<()>,
+    /// Commands along with the time they may be sent.
+    commands: mpsc::UnboundedSender<(Message, tokio::time::Instant)>,
+    limiter: Mutex<CommandLimiter>,
+}
+
+impl Connection {
+    fn new(
+        sink: MessageSink,
+        stream: MessageStream,
+        encoding: Encoding,
+        zlib_stream: bool,
+        health: GatewayHealth,
+    ) -> Connection {
+        let (outbox, rx) = mpsc::unbounded_channel();
+        let (commands, commands_rx) = mpsc::unbounded_channel();
+        tokio::spawn(Self::write_loop(sink, rx, commands_rx));
+        let (zombie_tx, zombie) = mpsc::unbounded_channel();
+        Connection {
+            stream,
+            inflater: Inflater::new(zlib_stream),
+            heartbeat: Arc::new(Heartbeat {
+                encoding,
+                outbox,
+                zombie: zombie_tx,
+                acked: AtomicBool::new(true),
+                health,
+            }),
+            zombie,
+            commands,
+            limiter: Mutex::new(CommandLimiter::new()),
+        }
+    }
+    /// Writes frames from the outbox as they come and queued commands once
+    /// their time has come.
+    async fn write_loop(
+        mut sink: MessageSink,
+        mut outbox: mpsc::UnboundedReceiver<Message>,
+        mut commands: mpsc::UnboundedReceiver<(Message, tokio::time::Instant)>,
+    ) {
+        let mut pending = None;
+        loop {
+            let msg = match pending.take() {
+                Some((msg, at)) => select!(
+                    v = outbox.recv() => {
+                        pending = Some((msg, at));
+                        v
+                    },
+                    _ = tokio::time::delay_until(at) => Some(msg),
+                ),
+                None => select!(
+                    v = outbox.recv() => v,
+                    v = commands.recv() => match v {
+                        Some((msg, at)) if at > tokio::time::Instant::now() => {
+                            pending = Some((msg, at));
+                            continue;
+                        }
+                        v => v.map(|(msg, _)| msg),
+                    },
+                ),
+            };
+            let msg = match msg {
+                Some(msg) => msg,
+                None => return,
+            };
+            if let Err(e) = sink.send(msg).await {
+                println!("send() failed: {}", e);
+                return;
+            }
+        }
+    }
+    fn send(&self, msg: Message) -> bool {
+        self.heartbeat.outbox.send(msg).is_ok()
+    }
+    fn send_payload(&self, payload: &serde_json::Value) -> bool {
+        self.send(self.heartbeat.encoding.encode(payload))
+    }
+    /// Queues a command behind the gateway rate limits. Returns how long it
+    /// will be held back, or `None` if the connection is gone.
+    fn send_command(&self, payload: &serde_json::Value) -> Option<std::time::Duration> {
+        let kind = if payload["op"] == 3 {
+            CommandKind::PresenceUpdate
+        } else {
+            CommandKind::Other
+        };
+        let at = self.limiter.lock().unwrap().reserve(kind);
+        let msg = self.heartbeat.encoding.encode(payload);
+        self.commands.send((msg, at)).ok()?;
+        Some(at.saturating_duration_since(tokio::time::Instant::now()))
+    }
+    fn start_heartbeat(&self, hello: &HelloMessage, seq: Arc<AtomicU64>) {
+        let period = std::time::Duration::from_millis(hello.heartbeat_interval);
+        tokio::spawn(Self::heartbeat_loop(
+            Arc::downgrade(&self.heartbeat),
+            period,
+            seq,
+        ));
+    }
+    async fn heartbeat_loop(hb: Weak<Heartbeat>, period: std::time::Duration, seq: Arc<AtomicU64>) {
+        let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        loop {
+            interval.tick().await;
+            let hb = match hb.upgrade() {
+                Some(hb) => hb,
+                None => return,
+            };
+            if !hb.acked.swap(false, Ordering::SeqCst) {
+                println!("Heartbeat was not acknowledged, dropping zombie connection");
+                let _ = hb.zombie.send(());
+                return;
+            }
+            let payload = heartbeat_payload(&seq);
+            println!("Heartbeating... {}", payload);
+            hb.health.heartbeat_sent();
+            if hb.outbox.send(hb.encoding.encode(&payload)).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+fn heartbeat_payload(seq: &AtomicU64) -> serde_json::Value {
+    json!({
+        "op": 1,
+        "d": seq.load(Ordering::SeqCst)
+    })
+}
+
+/// Gateway intents, a bitset selecting which groups of events Discord sends.
+/// GUILD_MEMBERS and GUILD_PRESENCES are privileged and must also be enabled
+/// for the bot in the developer portal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Intents(pub u64);
+
+impl Intents {
+    pub const GUILDS: Intents = Intents(1 << 0);
+    pub const GUILD_MEMBERS: Intents = Intents(1 << 1);
+    pub const GUILD_BANS: Intents = Intents(1 << 2);
+    pub const GUILD_EMOJIS: Intents = Intents(1 << 3);
+    pub const GUILD_INTEGRATIONS: Intents = Intents(1 << 4);
+    pub const GUILD_WEBHOOKS: Intents = Intents(1 << 5);
+    pub const GUILD_INVITES: Intents = Intents(1 << 6);
+    pub const GUILD_VOICE_STATES: Intents = Intents(1 << 7);
+    pub const GUILD_PRESENCES: Intents = Intents(1 << 8);
+    pub const GUILD_MESSAGES: Intents = Intents(1 << 9);
+    pub const GUILD_MESSAGE_REACTIONS: Intents = Intents(1 << 10);
+    pub const GUILD_MESSAGE_TYPING: Intents = Intents(1 << 11);
+    pub const DIRECT_MESSAGES: Intents = Intents(1 << 12);
+    pub const DIRECT_MESSAGE_REACTIONS: Intents = Intents(1 << 13);
+    pub const DIRECT_MESSAGE_TYPING: Intents = Intents(1 << 14);
+
+    pub fn contains(self, other: Intents) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl std::ops::BitOr for Intents {
+    type Output = Intents;
+    fn bitor(self, rhs: Intents) -> Intents {
+        Intents(self.0 | rhs.0)
+    }
+}
+
+/// Online status shown for the bot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Online,
+    Dnd,
+    Idle,
+    Invisible,
+    Offline,
+}
+
+impl Status {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Status::Online => "online",
+            Status::Dnd => "dnd",
+            Status::Idle => "idle",
+            Status::Invisible => "invisible",
+            Status::Offline => "offline",
+        }
+    }
+}
+
+/// Kinds of activity a bot can show. Custom statuses are for users only.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ActivityType {
+    Playing = 0,
+    Streaming = 1,
+    Listening = 2,
+    Watching = 3,
+}
+
+/// The bot's own presence, for Identify and Presence Update (op 3), e.g.
+/// `Presence::new(Status::Online).activity(ActivityType::Watching, "120 users")`
+/// to show "Watching 120 users".
+#[derive(Debug, Clone, PartialEq)]
+pub struct Presence {
+    status: Status,
+    /// Milliseconds since the Unix epoch at which the bot went idle.
+    since: Option<u64>,
+    activities: Vec<serde_json::Value>,
+    afk: bool,
+}
+
+impl Presence {
+    pub fn new(status: Status) -> Presence {
+        let since = if status == Status::Idle {
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .ok()
+        } else {
+            None
+        };
+        Presence {
+            status,
+            since,
+            activities: vec![],
+            afk: false,
+        }
+    }
+    pub fn activity(mut self, kind: ActivityType, name: &str) -> Presence {
+        self.activities
+            .push(json!({ "name": name, "type": kind as u32 }));
+        self
+    }
+    /// A Streaming activity linking to a Twitch or YouTube `url`.
+    pub fn streaming(mut self, name: &str, url: &str) -> Presence {
+        self.activities.push(json!({
+            "name": name,
+            "type": ActivityType::Streaming as u32,
+            "url": url
+        }));
+        self
+    }
+    pub fn afk(mut self, afk: bool) -> Presence {
+        self.afk = afk;
+        self
+    }
+    fn payload(&self) -> serde_json::Value {
+        json!({
+            "since": self.since,
+            "status": self.status.as_str(),
+            "afk": self.afk,
+            // gateway v6 only shows `game`; `activities` replaces it in later versions
+            "game": self.activities.first(),
+            "activities": self.activities
+        })
+    }
+}
+
+/// Optional fields of the Identify payload. Unset fields are left out so
+/// Discord applies its own defaults.
+#[derive(Debug, Clone, Default)]
+pub struct Identify {
+    intents: Option<Intents>,
+    shard: Option<(u64, u64)>,
+    large_threshold: Option<u32>,
+    compress: bool,
+    presence: Option<Presence>,
+}
+
+impl Identify {
+    pub fn new() -> Identify {
+        Identify::default()
+    }
+    pub fn intents(mut self, intents: Intents) -> Identify {
+        self.intents = Some(intents);
+        self
+    }
+    /// Identify as shard `id` out of `count`.
+    pub fn shard(mut self, id: u64, count: u64) -> Identify {
+        self.shard = Some((id, count));
+        self
+    }
+    /// Member count (50-250) above which a guild's offline members are left
+    /// out of GUILD_CREATE.
+    pub fn large_threshold(mut self, threshold: u32) -> Identify {
+        self.large_threshold = Some(threshold);
+        self
+    }
+    pub fn compress(mut self, compress: bool) -> Identify {
+        self.compress = compress;
+        self
+    }
+    /// Presence to come online with.
+    pub fn presence(mut self, presence: Presence) -> Identify {
+        self.presence = Some(presence);
+        self
+    }
+    fn payload(&self, token: &str) -> serde_json::Value {
+        let mut d = json!({
+            "token": token,
+            "properties": {
+                "$os": std::env::consts::OS,
+                "$browser": env!("CARGO_PKG_NAME"),
+                "$device": env!("CARGO_PKG_NAME")
+            }
+        });
+        if let Some(intents) = self.intents {
+            d["intents"] = json!(intents.0);
+        }
+        if let Some((id, count)) = self.shard {
+            d["shard"] = json!([id, count]);
+        }
+        if let Some(threshold) = self.large_threshold {
+            d["large_threshold"] = json!(threshold);
+        }
+        if self.compress {
+            d["compress"] = json!(true);
+        }
+        if let Some(presence) = &self.presence {
+            d["presence"] = presence.payload();
+        }
+        json!({ "op": 2, "d": d })
+    }
+}
+
+/// Which members of a guild Request Guild Members (op 8) asks for. Listing
+/// all members requires the GUILD_MEMBERS intent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MemberQuery {
+    All,
+    /// Up to 100 members whose username starts with the prefix.
+    Prefix(String),
+    UserIds(Vec<String>),
+}
+
+/// A gateway command queued for a client by another task, e.g. through
+/// `ShardHandle`. It is sent while the client's owner reads events.
+#[derive(Debug)]
+pub enum GatewayCommand {
+    SetPresence {
+        presence: Presence,
+        /// Told how long the update was held back by the gateway rate limits.
+        delayed: oneshot::Sender<DiscordResult<std::time::Duration>>,
+    },
+    RequestGuildMembers {
+        guild_id: String,
+        query: MemberQuery,
+        nonce: String,
+    },
+}
+
+/// Session starts left today, as reported by `/gateway/bot` and counted down
+/// locally since. Shared between a client and its siblings.
+#[derive(Debug, Default)]
+struct SessionStarts {
+    remaining: Option<u32>,
+    reset_at: Option<tokio::time::Instant>,
+}
+
+impl SessionStarts {
+    fn update(&mut self, limit: &SessionStartLimit) {
+        self.remaining = Some(limit.remaining);
+        self.reset_at =
+            Some(tokio::time::Instant::now() + std::time::Duration::from_millis(limit.reset_after));
+    }
+    /// True when the count is unknown, at first and again after each reset,
+    /// and should be fetched from `/gateway/bot`.
+    fn is_stale(&self) -> bool {
+        self.remaining.is_none()
+            || self
+                .reset_at
+                .is_some_and(|reset_at| reset_at <= tokio::time::Instant::now())
+    }
+    /// Counts one session start. Returns how long to wait first if the
+    /// reserve has been reached.
+    fn take(&mut self) -> Option<std::time::Duration> {
+        let now = tokio::time::Instant::now();
+        if self.reset_at.is_some_and(|reset_at| reset_at <= now) {
+            *self = SessionStarts::default();
+        }
+        match self.remaining {
+            Some(remaining) if remaining <= SESSION_START_RESERVE => {
+                let wait = self.reset_at.map(|reset_at| reset_at - now);
+                // the count after the reset is unknown until the next /gateway/bot
+                *self = SessionStarts::default();
+                wait
+            }
+            Some(remaining) => {
+                self.remaining = Some(remaining - 1);
+                None
+            }
+            None => None,
+        }
+    }
+}
+
 pub struct DiscordClient {
     raw_tok: String,
-    wss: async_tungstenite::WebSocketStream<
-        async_tungstenite::stream::Stream<TcpStream, TlsStream<TcpStream>>,
-    >,
+    conn: Option<Connection>,
+    encoding: Encoding,
+    zlib_stream: bool,
+    identify: Identify,
     pub my_id: String,
     pub session_id: String,
-    client: reqwest::Client,
-    auth_header: String,
-    heartbeat_interval: Option<tokio::time::Interval>,
-    last_seq: u64,
+    http: Http,
+    /// Taken from `/gateway/bot` before the first connection unless set.
+    gateway_url: Option<String>,
+    sessions: Arc<std::sync::Mutex<SessionStarts>>,
+    recorder: Option<Recorder>,
+    transport: Arc<dyn Transport>,
+    /// Close frame of the last connection, until acted on.
+    closed: Option<(CloseReason, String)>,
+    last_seq: Arc<AtomicU64>,
+    health: GatewayHealth,
+    /// Commands from other tasks, sent once the session is `live`.
+    queue: mpsc::UnboundedSender<GatewayCommand>,
+    queued: mpsc::UnboundedReceiver<GatewayCommand>,
+    /// True from Ready or Resumed until the connection is lost.
+    live: bool,
 }
 
 impl DiscordClient {
-    pub async fn new(tok: String) -> DiscordClient {
-        let client = reqwest::Client::builder()
-            .user_agent("DiscordBot (https://github.com/ras0219, 0)")
-            .build()
-            .unwrap();
-
-        let auth_header = format!("Bot {}", tok);
-
-        let mut headers = [Header {
-            name: "Authorization",
-            value: auth_header.as_bytes(),
-        }];
-        let mut req = httparse::Request::new(&mut headers);
-        req.path = Some(&"wss://gateway.discord.gg/?v=6&encoding=json");
-        req.method = Some("GET");
-        req.version = Some(b'1');
-
-        let (wss, _) = async_tungstenite::async_std::connect_async(req)
-            .await
-            .unwrap();
-
-        let mut dclient = DiscordClient {
+    /// A gateway session for the bot `http` authenticates as. The connection
+    /// is opened by the first `next_msg`.
+    pub fn new(http: Http) -> DiscordClient {
+        let (queue, queued) = mpsc::unbounded_channel();
+        DiscordClient {
             my_id: "".to_string(),
-            raw_tok: tok,
-            wss,
+            raw_tok: http.token().to_string(),
+            conn: None,
+            encoding: Encoding::Json,
+            zlib_stream: true,
             session_id: "".to_string(),
-            client,
-            auth_header,
-            heartbeat_interval: None,
-            last_seq: 0,
-        };
-        dclient
-    }
-    pub async fn create_channel(&mut self, id: &str) -> Channel {
-        let mut p = HashMap::new();
-        p.insert("recipient_id", id);
-        let msg = &self
-            .client
-            .post("https://discordapp.com/api/v6/users/@me/channels")
-            .json(&p)
-            .header("Authorization", &self.auth_header)
-            .send()
-            .await
+            http,
+            gateway_url: None,
+            sessions: Arc::default(),
+            recorder: None,
+            transport: Arc::new(WebSocketTransport),
+            closed: None,
+            last_seq: Arc::new(AtomicU64::new(0)),
+            identify: Identify::new(),
+            health: GatewayHealth::default(),
+            queue,
+            queued,
+            live: false,
+        }
+    }
+    /// The REST handle this session was created with.
+    pub fn http(&self) -> &Http {
+        &self.http
+    }
+    /// Enables or disables `compress=zlib-stream` for future connections. It is
+    /// on by default.
+    pub fn set_zlib_stream(&mut self, enabled: bool) {
+        self.zlib_stream = enabled;
+    }
+    /// Selects the payload encoding for future connections. JSON by default.
+    pub fn set_encoding(&mut self, encoding: Encoding) {
+        self.encoding = encoding;
+    }
+    /// Points future gateway connections at another server, e.g.
+    /// `ws://127.0.0.1:8081`, instead of the one `/gateway/bot` names.
+    pub fn set_gateway_url(&mut self, url: &str) {
+        self.gateway_url = Some(url.trim_end_matches('/').to_string());
+    }
+    /// Fetches `/gateway/bot`, taking the gateway URL from it unless one was
+    /// set and refreshing the count of session starts left today.
+    pub async fn gateway_bot(&mut self) -> DiscordResult<GatewayBot> {
+        let bot = self.http.get_gateway_bot().await?;
+        if self.gateway_url.is_none() {
+            self.gateway_url = Some(bot.url.trim_end_matches('/').to_string());
+        }
+        self.sessions
+            .lock()
             .unwrap()
-            .text()
-            .await
-            .unwrap();
-        println!("create_channel() -> {}", msg);
-        serde_json::from_str::<Channel>(msg).unwrap()
-    }
-    pub async fn create_msg(&mut self, chan_id: &str, content: &str) {
-        // let payload = json!({
-        //   "content": "Hello, World!",
-        //   "tts": false,
-        //   "embed": {
-        //     "title": "Hello, Embed!",
-        //     "description": "This is an embedded message."
-        //   }
-        // })
-        // .to_string();
-        for _ in 1..3 {
-            let payload = json!({ "content": content }).to_string();
-            let res = &self
-                .client
-                .post(&format!(
-                    "https://discordapp.com/api/v6/channels/{}/messages",
-                    chan_id
-                ))
-                .body(payload)
-                .header("Authorization", &self.auth_header)
-                .header("Content-Type", "application/json")
-                .send()
-                .await
-                .unwrap()
-                .text()
-                .await
-                .unwrap();
-            println!("create_msg: {}", res);
-            match serde_json::from_str::<RateLimited<CreateMessageResponse>>(res).unwrap() {
-                RateLimited::Success { .. } => {
-                    break;
+            .update(&bot.session_start_limit);
+        Ok(bot)
+    }
+    /// Writes every gateway payload received from now on to `recorder`.
+    pub fn set_recorder(&mut self, recorder: Recorder) {
+        self.recorder = Some(recorder);
+    }
+    /// Opens future gateway connections through `transport` instead of a
+    /// websocket.
+    pub fn set_transport(&mut self, transport: Arc<dyn Transport>) {
+        self.transport = transport;
+    }
+    /// A new client for the same bot with the same settings and REST handle,
+    /// but without a gateway session of its own.
+    pub fn sibling(&self) -> DiscordClient {
+        let (queue, queued) = mpsc::unbounded_channel();
+        DiscordClient {
+            my_id: self.my_id.clone(),
+            raw_tok: self.raw_tok.clone(),
+            conn: None,
+            encoding: self.encoding,
+            zlib_stream: self.zlib_stream,
+            session_id: "".to_string(),
+            http: self.http.clone(),
+            gateway_url: self.gateway_url.clone(),
+            sessions: self.sessions.clone(),
+            recorder: self.recorder.clone(),
+            transport: self.transport.clone(),
+            closed: None,
+            last_seq: Arc::new(AtomicU64::new(0)),
+            identify: self.identify.clone(),
+            health: GatewayHealth::default(),
+            queue,
+            queued,
+            live: false,
+        }
+    }
+    /// Heartbeat latency, time since the last dispatch and how often the
+    /// session had to reconnect, resume or start over.
+    pub fn stats(&self) -> GatewayStats {
+        self.health.stats()
+    }
+    /// A handle to this session's health that stays current, for reading
+    /// from other tasks.
+    pub fn health(&self) -> GatewayHealth {
+        self.health.clone()
+    }
+    /// Queues commands from other tasks. They go out while `next_msg` is
+    /// being awaited and the session is up, in the order queued.
+    pub fn queue(&self) -> mpsc::UnboundedSender<GatewayCommand> {
+        self.queue.clone()
+    }
+    async fn connect(&mut self) -> DiscordResult<Connection> {
+        let gateway_url = match &self.gateway_url {
+            Some(url) => url.clone(),
+            None => {
+                self.gateway_bot().await?;
+                self.gateway_url.clone().unwrap()
+            }
+        };
+        let mut url = format!(
+            "{}/?v={}&encoding={}",
+            gateway_url,
+            API_VERSION,
+            self.encoding.query()
+        );
+        if self.zlib_stream {
+            url.push_str("&compress=zlib-stream");
+        }
+        let (sink, stream) = self
+            .transport
+            .connect(url, format!("Bot {}", self.raw_tok))
+            .await?;
+        Ok(Connection::new(
+            sink,
+            stream,
+            self.encoding,
+            self.zlib_stream,
+            self.health.clone(),
+        ))
+    }
+    /// Drops the current websocket (if any), opens a new one and either resumes
+    /// the previous session or identifies from scratch. Retries until the
+    /// gateway answers with Hello.
+    async fn reconnect(&mut self) {
+        let mut backoff = std::time::Duration::from_secs(1);
+        if self.conn.is_some() {
+            self.health.update(|s| s.reconnects += 1);
+        }
+        self.live = false;
+        loop {
+            println!("Connecting to gateway...");
+            if let Some(conn) = &self.conn {
+                conn.send(Message::Close(None));
+            }
+            match self.connect().await {
+                Ok(conn) => self.conn = Some(conn),
+                Err(e) => {
+                    println!("reconnect() failed: {}", e);
+                    tokio::time::delay_for(backoff).await;
+                    backoff = std::cmp::min(backoff * 2, std::time::Duration::from_secs(60));
+                    continue;
                 }
-                RateLimited::RateLimit { retry_after, .. } => {
-                    tokio::time::delay_for(std::time::Duration::from_millis(retry_after.into()))
-                        .await;
+            }
+            match self.recv().await {
+                Some(Ok(DiscordMessage::Hello { d })) => self.set_heartbeat(&d),
+                other => {
+                    println!("reconnect() expected Hello, got {:?}", other);
+                    tokio::time::delay_for(backoff).await;
+                    continue;
                 }
             }
+            if self.session_id.is_empty() {
+                self.send_identify().await;
+            } else {
+                self.send_resume().await;
+            }
+            return;
+        }
+    }
+    fn set_heartbeat(&mut self, hello: &HelloMessage) {
+        if let Some(conn) = &self.conn {
+            conn.start_heartbeat(hello, self.last_seq.clone());
+        }
+    }
+    /// Sends a gateway command, queueing it if it would exceed Discord's
+    /// limit of 120 commands a minute (five for presence updates). Returns
+    /// how long it was held back. Heartbeats never wait behind commands.
+    pub fn send_command(
+        &mut self,
+        payload: serde_json::Value,
+    ) -> DiscordResult<std::time::Duration> {
+        let conn = self
+            .conn
+            .as_ref()
+            .ok_or(DiscordError::Gateway(tungstenite::Error::ConnectionClosed))?;
+        let delay = conn
+            .send_command(&payload)
+            .ok_or(DiscordError::Gateway(tungstenite::Error::ConnectionClosed))?;
+        if delay > std::time::Duration::from_millis(0) {
+            println!(
+                "Gateway command op {} delayed {:?} by rate limit",
+                payload["op"], delay
+            );
         }
+        Ok(delay)
     }
 
-    pub async fn next_msg(&mut self) -> DiscordMessage {
+    /// Reads the next gateway payload, answering heartbeat requests and ACKs
+    /// along the way. Returns `None` once the connection is closed, broken or
+    /// stopped acknowledging heartbeats, or if it was never opened; a payload
+    /// that fails to decode is an error but leaves the connection usable.
+    async fn recv(&mut self) -> Option<DiscordResult<DiscordMessage>> {
         loop {
-            let msg = match &mut self.heartbeat_interval {
-                Some(i) => select!(
-                    v = self.wss.next() => v.unwrap().unwrap(),
-                    _ = i.next() => {
-                        let payload = json!({
-                            "op": 1,
-                            "d": self.last_seq
-                        })
-                        .to_string();
-                        println!("Heartbeating... {}", payload);
-                        self.wss.send(Message::text(payload)).await.unwrap();
-                        continue;
-                    },
-                ),
-                None => self.wss.next().await.unwrap().unwrap(),
+            let live = self.live;
+            let conn = self.conn.as_mut()?;
+            let msg = select!(
+                v = conn.stream.next() => Ok(v),
+                _ = conn.zombie.recv() => return None,
+                Some(command) = self.queued.recv(), if live => Err(command),
+            );
+            let msg = match msg {
+                Ok(Some(Ok(msg))) => msg,
+                Err(command) => {
+                    self.run_command(command);
+                    continue;
+                }
+                Ok(Some(Err(e))) => {
+                    println!("recv() failed: {}", e);
+                    return None;
+                }
+                Ok(None) => return None,
             };
-            if !msg.is_text() {
-                continue;
+            let data = match msg {
+                Message::Text(text) => text.into_bytes(),
+                Message::Binary(data) => match conn.inflater.push(&data) {
+                    Ok(Some(data)) => data,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        println!("inflate failed: {}", e);
+                        return None;
+                    }
+                },
+                Message::Close(frame) => {
+                    println!("Connection closed: {:?}", frame);
+                    self.closed = frame
+                        .map(|f| (CloseReason::from_code(f.code.into()), f.reason.into_owned()));
+                    return None;
+                }
+                _ => continue,
+            };
+            if let Some(recorder) = &self.recorder {
+                recorder.record(self.identify.shard, conn.heartbeat.encoding, &data);
             }
-            let dismsg = serde_json::from_str::<DiscordMessage>(&msg.to_string());
+            let dismsg = conn.heartbeat.encoding.decode(&data);
             println!("DisMsg: {:?}", dismsg);
             match dismsg {
-                Err(e) => {
-                    println!("Msg: {}\n>>> {}", msg, &msg.to_string()[e.column()..]);
-                    panic!();
-                }
                 Ok(DiscordMessage::HeartbeatAck {}) => {
-                    continue;
+                    conn.heartbeat.acked.store(true, Ordering::SeqCst);
+                    self.health.heartbeat_acked();
+                }
+                Ok(DiscordMessage::Heartbeat {}) => {
+                    self.health.heartbeat_requested();
+                    conn.send_payload(&heartbeat_payload(&self.last_seq));
                 }
-                Ok(msg) => {
-                    self.last_seq = msg.seq().unwrap_or(self.last_seq);
-                    return msg;
+                dismsg => {
+                    if dismsg.as_ref().is_ok_and(|msg| msg.seq().is_some()) {
+                        self.health
+                            .update(|s| s.last_dispatch = Some(tokio::time::Instant::now()));
+                    }
+                    return Some(dismsg);
                 }
             }
         }
     }
-    pub async fn get_hello(&mut self) {
-        let msg = self.next_msg().await;
 
-        match msg {
-            DiscordMessage::Hello { d } => {
-                self.heartbeat_interval = Some(tokio::time::interval(
-                    std::time::Duration::from_millis(d.heartbeat_interval),
-                ))
+    /// Returns the next dispatch from the gateway. The connection is opened on
+    /// first use; dropped connections, Reconnect requests and invalidated
+    /// sessions are handled transparently by reconnecting and resuming (or
+    /// re-identifying). A fresh `Ready` is passed through so callers can reset
+    /// their view of the world. Errors are payloads that could not be decoded;
+    /// the stream can be read on after them. The exception is
+    /// `DiscordError::GatewayClosed`, returned when Discord closes the
+    /// connection for good, e.g. over a bad token.
+    pub async fn next_msg(&mut self) -> DiscordResult<DiscordMessage> {
+        loop {
+            match self.recv().await {
+                None => {
+                    if let Some((reason, message)) = self.closed.take() {
+                        if reason.is_fatal() {
+                            self.conn = None;
+                            return Err(DiscordError::GatewayClosed { reason, message });
+                        }
+                        if reason.invalidates_session() {
+                            self.health.update(|s| s.invalid_sessions += 1);
+                            self.session_id.clear();
+                        }
+                    }
+                    self.reconnect().await;
+                }
+                Some(Ok(DiscordMessage::Reconnect {})) => {
+                    self.reconnect().await;
+                }
+                Some(Err(e)) => return Err(e),
+                Some(Ok(DiscordMessage::InvalidSession {})) => {
+                    self.health.update(|s| s.invalid_sessions += 1);
+                    self.session_id.clear();
+                    self.live = false;
+                    // Discord asks clients to wait a random 1-5 seconds before identifying again
+                    let jitter = std::time::SystemTime::now()
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .map(|d| d.subsec_millis() as u64 * 4)
+                        .unwrap_or(0);
+                    tokio::time::delay_for(std::time::Duration::from_millis(1000 + jitter)).await;
+                    self.send_identify().await;
+                }
+                Some(Ok(DiscordMessage::Hello { d })) => self.set_heartbeat(&d),
+                Some(Ok(msg)) => {
+                    match &msg {
+                        DiscordMessage::Ready { d, .. } => {
+                            self.my_id = d.user.id.clone();
+                            self.session_id = d.session_id.clone();
+                            self.live = true;
+                        }
+                        DiscordMessage::Resumed { d, .. } => {
+                            self.health.update(|s| s.resumes += 1);
+                            self.session_id = d.session_id.clone();
+                            self.live = true;
+                        }
+                        _ => {}
+                    }
+                    if let Some(s) = msg.seq() {
+                        self.last_seq.store(s, Ordering::SeqCst);
+                    }
+                    return Ok(msg);
+                }
+            }
+        }
+    }
+    pub async fn get_hello(&mut self) -> DiscordResult<()> {
+        if self.conn.is_none() {
+            self.conn = Some(self.connect().await?);
+        }
+        match self.recv().await {
+            Some(Ok(DiscordMessage::Hello { d })) => {
+                self.set_heartbeat(&d);
+                Ok(())
             }
-            _ => panic!(),
+            Some(Ok(other)) => Err(DiscordError::Decode(format!(
+                "expected Hello, got {:?}",
+                other
+            ))),
+            Some(Err(e)) => Err(e),
+            None => Err(DiscordError::Gateway(tungstenite::Error::ConnectionClosed)),
         }
     }
-    pub async fn resume(&mut self, session_id: String) {
+    async fn send_resume(&mut self) {
         let payload = json!({
             "op": 6,
             "d": {
                 "token": self.raw_tok,
-                "session_id": session_id,
-                "seq": 1337
+                "session_id": self.session_id,
+                "seq": self.last_seq.load(Ordering::SeqCst)
             }
-        })
-        .to_string();
-        self.wss.send(Message::text(payload)).await.unwrap();
+        });
+        let _ = self.send_command(payload);
+    }
+    pub async fn resume(&mut self, session_id: String) -> DiscordResult<()> {
+        self.session_id = session_id;
+        self.send_resume().await;
         loop {
-            let msg = self.next_msg().await;
-            match msg {
-                DiscordMessage::Resumed { d, .. } => {
-                    self.session_id = d.session_id;
+            match self.next_msg().await? {
+                DiscordMessage::Resumed { .. } | DiscordMessage::Ready { .. } => return Ok(()),
+                _ => {}
+            }
+        }
+    }
+    /// Sets the options used by `identify()` and by every re-identify after a
+    /// lost session.
+    pub fn set_identify(&mut self, identify: Identify) {
+        self.identify = identify;
+    }
+    /// Changes the bot's status and activities with Presence Update (op 3).
+    /// The presence is also used when identifying again later. Returns how
+    /// long the update was held back by the gateway rate limits.
+    pub fn set_presence(&mut self, presence: Presence) -> DiscordResult<std::time::Duration> {
+        let payload = json!({ "op": 3, "d": presence.payload() });
+        self.identify.presence = Some(presence);
+        self.send_command(payload)
+    }
+    /// Sends Request Guild Members (op 8) for `guild_id`, with presences.
+    /// Discord answers with GUILD_MEMBERS_CHUNK events carrying `nonce`,
+    /// which `next_msg` returns like any other; `ShardHandle` puts them back
+    /// together.
+    pub fn request_guild_members(
+        &mut self,
+        guild_id: &str,
+        query: MemberQuery,
+        nonce: &str,
+    ) -> DiscordResult<std::time::Duration> {
+        let mut d = json!({
+            "guild_id": guild_id,
+            "presences": true,
+            "nonce": nonce
+        });
+        match query {
+            MemberQuery::All => {
+                d["query"] = json!("");
+                d["limit"] = json!(0);
+            }
+            MemberQuery::Prefix(prefix) => {
+                d["query"] = json!(prefix);
+                d["limit"] = json!(100);
+            }
+            MemberQuery::UserIds(ids) => d["user_ids"] = json!(ids),
+        }
+        self.send_command(json!({ "op": 8, "d": d }))
+    }
+    fn run_command(&mut self, command: GatewayCommand) {
+        match command {
+            GatewayCommand::SetPresence { presence, delayed } => {
+                let _ = delayed.send(self.set_presence(presence));
+            }
+            GatewayCommand::RequestGuildMembers {
+                guild_id,
+                query,
+                nonce,
+            } => {
+                if let Err(e) = self.request_guild_members(&guild_id, query, &nonce) {
+                    println!("Queued gateway command failed: {}", e);
                 }
+            }
+        }
+    }
+    async fn send_identify(&mut self) {
+        let stale = self.sessions.lock().unwrap().is_stale();
+        if stale {
+            if let Err(e) = self.gateway_bot().await {
+                println!("Could not refresh session start limit: {}", e);
+            }
+        }
+        let wait = self.sessions.lock().unwrap().take();
+        if let Some(wait) = wait {
+            println!(
+                "Session starts are running out, waiting {:?} for the daily reset before identifying",
+                wait
+            );
+            tokio::time::delay_for(wait).await;
+        }
+        self.last_seq.store(0, Ordering::SeqCst);
+        let payload = self.identify.payload(&self.raw_tok);
+        let _ = self.send_command(payload);
+    }
+    pub async fn identify(&mut self) -> DiscordResult<ReadyMessage> {
+        self.send_identify().await;
+        loop {
+            if let DiscordMessage::Ready { d, .. } = self.next_msg().await? {
+                return Ok(d);
+            }
+        }
+    }
+}
 
-                DiscordMessage::InvalidSession {} => {
-                    self.identify().await;
-                    return;
-                }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use std::time::Duration;
 
-                _ => {}
+    async fn client() -> (DiscordClient, mpsc::UnboundedReceiver<MemoryPeer>) {
+        let (transport, listener) = MemoryTransport::new();
+        let mut http = Http::new("token").unwrap();
+        // no session start limit to refresh
+        http.set_offline(true);
+        let mut client = DiscordClient::new(http);
+        client.set_transport(Arc::new(transport));
+        client.set_gateway_url("wss://gateway.test");
+        client.set_zlib_stream(false);
+        (client, listener)
+    }
+
+    fn send(peer: &MemoryPeer, payload: Value) {
+        peer.tx
+            .send(Ok(Message::text(payload.to_string())))
+            .unwrap();
+    }
+
+    async fn recv(peer: &mut MemoryPeer) -> Value {
+        match peer.rx.recv().await.expect("client hung up") {
+            Message::Text(text) => serde_json::from_str(&text).unwrap(),
+            other => panic!("unexpected frame {:?}", other),
+        }
+    }
+
+    fn close(peer: &MemoryPeer, code: u16) {
+        let frame = tungstenite::protocol::CloseFrame {
+            code: code.into(),
+            reason: "".into(),
+        };
+        peer.tx.send(Ok(Message::Close(Some(frame)))).unwrap();
+    }
+
+    fn hello(interval: u64) -> Value {
+        json!({ "op": 10, "s": null, "t": null, "d": { "heartbeat_interval": interval } })
+    }
+
+    fn dispatch(t: &str, s: u64, d: Value) -> Value {
+        json!({ "op": 0, "t": t, "s": s, "d": d })
+    }
+
+    fn ready(s: u64) -> Value {
+        dispatch(
+            "READY",
+            s,
+            json!({ "v": 6, "user": { "id": "10" }, "session_id": "abc", "guilds": [] }),
+        )
+    }
+
+    #[tokio::test]
+    async fn identifies_then_resumes_after_reconnect() {
+        let (mut client, mut listener) = client().await;
+        let gateway = async {
+            let mut peer = listener.recv().await.unwrap();
+            assert!(peer.url.ends_with("/?v=6&encoding=json"), "{}", peer.url);
+            send(&peer, hello(45000));
+            let identify = recv(&mut peer).await;
+            assert_eq!(identify["op"], 2);
+            assert_eq!(identify["d"]["token"], "token");
+            send(&peer, ready(1));
+            send(&peer, dispatch("TYPING_START", 2, json!({})));
+            send(&peer, json!({ "op": 7, "s": null, "t": null, "d": null }));
+
+            let mut peer = listener.recv().await.unwrap();
+            send(&peer, hello(45000));
+            let resume = recv(&mut peer).await;
+            assert_eq!(
+                resume,
+                json!({ "op": 6, "d": { "token": "token", "session_id": "abc", "seq": 2 } })
+            );
+            send(
+                &peer,
+                dispatch("RESUMED", 3, json!({ "v": 6, "session_id": "abc" })),
+            );
+            peer
+        };
+        let session = async {
+            assert!(matches!(
+                client.next_msg().await,
+                Ok(DiscordMessage::Ready { s: 1, .. })
+            ));
+            assert!(matches!(
+                client.next_msg().await,
+                Ok(DiscordMessage::Unknown { s: 2, .. })
+            ));
+            assert!(matches!(
+                client.next_msg().await,
+                Ok(DiscordMessage::Resumed { s: 3, .. })
+            ));
+        };
+        tokio::join!(gateway, session);
+        assert_eq!(client.session_id, "abc");
+        assert_eq!(client.my_id, "10");
+    }
+
+    #[tokio::test]
+    async fn speaks_etf_without_zlib_stream() {
+        let (mut client, mut listener) = client().await;
+        client.set_encoding(Encoding::Etf);
+        let gateway = async {
+            let mut peer = listener.recv().await.unwrap();
+            assert!(peer.url.ends_with("/?v=6&encoding=etf"), "{}", peer.url);
+            let send = |payload: Value| {
+                let frame = Message::binary(discordetf::encode(&payload));
+                peer.tx.send(Ok(frame)).unwrap();
+            };
+            send(hello(45000));
+            send(ready(1));
+            send(json!({ "op": 1, "s": null, "t": null, "d": null }));
+            send(dispatch("TYPING_START", 2, json!({})));
+            let mut frames = vec![];
+            for _ in 0..2 {
+                match peer.rx.recv().await.expect("client hung up") {
+                    Message::Binary(data) => frames.push(discordetf::decode(&data).unwrap()),
+                    other => panic!("unexpected frame {:?}", other),
+                }
             }
+            // the heartbeat answer skips the command queue identify waits in
+            frames.sort_by_key(|frame| frame["op"].as_u64());
+            assert_eq!(frames[0], json!({ "op": 1, "d": 1 }));
+            assert_eq!(frames[1]["op"], 2);
+            assert_eq!(frames[1]["d"]["token"], "token");
+            peer
+        };
+        let session = async {
+            assert!(matches!(
+                client.next_msg().await,
+                Ok(DiscordMessage::Ready { s: 1, .. })
+            ));
+            assert_eq!(client.next_msg().await.unwrap().seq(), Some(2));
+        };
+        tokio::join!(gateway, session);
+        assert_eq!(client.session_id, "abc");
+    }
+
+    #[tokio::test]
+    async fn answers_heartbeat_requests_with_last_sequence() {
+        let (mut client, mut listener) = client().await;
+        let gateway = async {
+            let mut peer = listener.recv().await.unwrap();
+            send(&peer, hello(45000));
+            recv(&mut peer).await;
+            send(&peer, ready(5));
+            send(&peer, json!({ "op": 1, "s": null, "t": null, "d": null }));
+            assert_eq!(recv(&mut peer).await, json!({ "op": 1, "d": 5 }));
+            send(&peer, dispatch("TYPING_START", 6, json!({})));
+            peer
+        };
+        let session = async {
+            client.next_msg().await.unwrap();
+            assert_eq!(client.next_msg().await.unwrap().seq(), Some(6));
+        };
+        tokio::join!(gateway, session);
+    }
+
+    #[tokio::test]
+    async fn rate_limited_commands_queue_behind_heartbeats() {
+        let (mut client, mut listener) = client().await;
+        let gateway = async {
+            let peer = listener.recv().await.unwrap();
+            send(&peer, hello(45000));
+            send(&peer, ready(1));
+            peer
+        };
+        let (mut peer, _) = tokio::join!(gateway, client.next_msg());
+        assert_eq!(recv(&mut peer).await["op"], 2);
+
+        let presence = json!({ "op": 3, "d": { "since": null, "activities": [], "status": "idle", "afk": false } });
+        for _ in 0..5 {
+            assert_eq!(
+                client.send_command(presence.clone()).unwrap(),
+                Duration::from_secs(0)
+            );
+        }
+        assert!(client.send_command(presence).unwrap() > Duration::from_secs(59));
+        // everything after the held back presence update waits with it
+        let members = json!({ "op": 8, "d": { "guild_id": "1", "query": "", "limit": 0 } });
+        assert!(client.send_command(members).unwrap() > Duration::from_secs(59));
+
+        send(&peer, json!({ "op": 1, "s": null, "t": null, "d": null }));
+        send(&peer, dispatch("TYPING_START", 2, json!({})));
+        client.next_msg().await.unwrap();
+        let mut ops = vec![];
+        for _ in 0..6 {
+            ops.push(recv(&mut peer).await["op"].as_u64().unwrap());
         }
+        ops.sort();
+        assert_eq!(ops, [1, 3, 3, 3, 3, 3]);
+        let held = tokio::time::timeout(Duration::from_millis(100), peer.rx.recv()).await;
+        assert!(held.is_err(), "sent {:?} early", held);
     }
-    pub async fn identify(&mut self) -> ReadyMessage {
-        let payload = json!(
-        {
-            "op": 2,
-            "d": {
-              "token": self.raw_tok,
-              "properties": {
-                "$os": "linux",
-                "$browser": "my_library",
-                "$device": "my_library"
-              }
+
+    #[tokio::test]
+    async fn drops_connection_when_heartbeats_go_unacknowledged() {
+        let (mut client, mut listener) = client().await;
+        let gateway = async {
+            let mut peer = listener.recv().await.unwrap();
+            send(&peer, hello(20));
+            recv(&mut peer).await;
+            send(&peer, ready(1));
+            // never ACK: the client heartbeats once, then gives up on us
+            assert_eq!(recv(&mut peer).await["op"], 1);
+
+            let mut peer = listener.recv().await.unwrap();
+            send(&peer, hello(45000));
+            assert_eq!(recv(&mut peer).await["op"], 6);
+            send(
+                &peer,
+                dispatch("RESUMED", 2, json!({ "v": 6, "session_id": "abc" })),
+            );
+            peer
+        };
+        let session = async {
+            client.next_msg().await.unwrap();
+            assert!(matches!(
+                client.next_msg().await,
+                Ok(DiscordMessage::Resumed { .. })
+            ));
+        };
+        tokio::join!(gateway, session);
+    }
+
+    #[tokio::test]
+    async fn resumes_or_identifies_depending_on_close_code() {
+        let (mut client, mut listener) = client().await;
+        let gateway = async {
+            let mut peer = listener.recv().await.unwrap();
+            send(&peer, hello(45000));
+            recv(&mut peer).await;
+            send(&peer, ready(1));
+            close(&peer, 4000);
+
+            let mut peer = listener.recv().await.unwrap();
+            send(&peer, hello(45000));
+            assert_eq!(recv(&mut peer).await["op"], 6, "4000 should resume");
+            close(&peer, 4009);
+
+            let mut peer = listener.recv().await.unwrap();
+            send(&peer, hello(45000));
+            assert_eq!(recv(&mut peer).await["op"], 2, "4009 should identify");
+            send(&peer, ready(1));
+            peer
+        };
+        let session = async {
+            client.next_msg().await.unwrap();
+            assert!(matches!(
+                client.next_msg().await,
+                Ok(DiscordMessage::Ready { .. })
+            ));
+        };
+        tokio::join!(gateway, session);
+        // only the close that lost the session counts
+        assert_eq!(client.stats().invalid_sessions, 1);
+    }
+
+    #[tokio::test]
+    async fn presence_updates_carry_over_to_the_next_identify() {
+        let (mut client, mut listener) = client().await;
+        client.set_identify(
+            Identify::new().presence(
+                Presence::new(Status::Online).activity(ActivityType::Watching, "120 users"),
+            ),
+        );
+        let gateway = async {
+            let mut peer = listener.recv().await.unwrap();
+            send(&peer, hello(45000));
+            let identify = recv(&mut peer).await;
+            assert_eq!(identify["d"]["presence"]["status"], "online");
+            assert_eq!(
+                identify["d"]["presence"]["activities"],
+                json!([{ "name": "120 users", "type": 3 }])
+            );
+            send(&peer, ready(1));
+            peer
+        };
+        let (mut peer, _) = tokio::join!(gateway, client.next_msg());
+
+        client
+            .set_presence(Presence::new(Status::Idle).afk(true))
+            .unwrap();
+        let update = recv(&mut peer).await;
+        assert_eq!(update["op"], 3);
+        assert_eq!(update["d"]["status"], "idle");
+        assert_eq!(update["d"]["afk"], true);
+        assert!(update["d"]["since"].is_u64());
+        assert_eq!(update["d"]["activities"], json!([]));
+
+        let gateway = async {
+            close(&peer, 4009);
+            let mut peer = listener.recv().await.unwrap();
+            send(&peer, hello(45000));
+            let identify = recv(&mut peer).await;
+            assert_eq!(identify["d"]["presence"]["status"], "idle");
+            send(&peer, ready(1));
+            peer
+        };
+        let (_peer, ready) = tokio::join!(gateway, client.next_msg());
+        assert!(matches!(ready, Ok(DiscordMessage::Ready { .. })));
+    }
+
+    #[tokio::test]
+    async fn requested_heartbeats_leave_one_in_flight_alone() {
+        let health = GatewayHealth::default();
+        health.heartbeat_sent();
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+        health.heartbeat_requested();
+        health.heartbeat_acked();
+        assert!(health.stats().latency.unwrap() >= Duration::from_millis(50));
+
+        health.heartbeat_requested();
+        health.heartbeat_acked();
+        assert!(health.stats().latency.unwrap() < Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn queued_commands_wait_for_the_session() {
+        let (mut client, mut listener) = client().await;
+        let queue = client.queue();
+        queue
+            .send(GatewayCommand::RequestGuildMembers {
+                guild_id: "50".to_string(),
+                query: MemberQuery::UserIds(vec!["1".to_string(), "2".to_string()]),
+                nonce: "n1".to_string(),
+            })
+            .unwrap();
+        let gateway = async {
+            let mut peer = listener.recv().await.unwrap();
+            send(&peer, hello(45000));
+            assert_eq!(recv(&mut peer).await["op"], 2);
+            send(&peer, ready(1));
+            let request = recv(&mut peer).await;
+            assert_eq!(request["op"], 8);
+            assert_eq!(request["d"]["guild_id"], "50");
+            assert_eq!(request["d"]["user_ids"], json!(["1", "2"]));
+            assert_eq!(request["d"]["presences"], true);
+            assert_eq!(request["d"]["nonce"], "n1");
+            let chunk = json!({
+                "guild_id": "50",
+                "members": [{ "user": { "id": "1" }, "roles": [] }],
+                "chunk_index": 0,
+                "chunk_count": 1,
+                "not_found": ["2"],
+                "nonce": "n1"
+            });
+            send(&peer, dispatch("GUILD_MEMBERS_CHUNK", 2, chunk));
+            peer
+        };
+        let session = async {
+            client.next_msg().await.unwrap();
+            match client.next_msg().await {
+                Ok(DiscordMessage::GuildMembersChunk { d, .. }) => {
+                    assert_eq!(d.nonce.as_deref(), Some("n1"));
+                    assert_eq!(d.not_found, ["2"]);
+                }
+                other => panic!("unexpected {:?}", other),
             }
-          })
-        .to_string();
-
-        self.wss.send(Message::text(payload)).await.unwrap();
-        let msg = self.next_msg().await;
-        match msg {
-            DiscordMessage::Ready { d, .. } => {
-                self.my_id = d.user.id.clone();
-                self.session_id = d.session_id.clone();
-                d
+        };
+        tokio::join!(gateway, session);
+    }
+
+    #[tokio::test]
+    async fn tracks_latency_and_reconnects() {
+        let (mut client, mut listener) = client().await;
+        assert_eq!(client.stats().latency, None);
+        let gateway = async {
+            let mut peer = listener.recv().await.unwrap();
+            send(&peer, hello(20));
+            recv(&mut peer).await;
+            send(&peer, ready(1));
+            assert_eq!(recv(&mut peer).await["op"], 1);
+            send(&peer, json!({ "op": 11, "s": null, "t": null, "d": null }));
+            close(&peer, 4000);
+
+            let mut peer = listener.recv().await.unwrap();
+            send(&peer, hello(45000));
+            assert_eq!(recv(&mut peer).await["op"], 6);
+            send(
+                &peer,
+                dispatch("RESUMED", 2, json!({ "v": 6, "session_id": "abc" })),
+            );
+            peer
+        };
+        let session = async {
+            client.next_msg().await.unwrap();
+            client.next_msg().await.unwrap();
+        };
+        tokio::join!(gateway, session);
+        let stats = client.stats();
+        assert!(stats.latency.is_some());
+        assert!(stats.since_last_dispatch.unwrap() < Duration::from_secs(5));
+        assert_eq!((stats.reconnects, stats.resumes), (1, 1));
+        assert_eq!(stats.invalid_sessions, 0);
+    }
+
+    #[tokio::test]
+    async fn fatal_close_codes_are_returned() {
+        let (mut client, mut listener) = client().await;
+        let gateway = async {
+            let mut peer = listener.recv().await.unwrap();
+            send(&peer, hello(45000));
+            recv(&mut peer).await;
+            close(&peer, 4004);
+            peer
+        };
+        let (_peer, result) = tokio::join!(gateway, client.next_msg());
+        match result {
+            Err(DiscordError::GatewayClosed { reason, .. }) => {
+                assert_eq!(reason, CloseReason::AuthenticationFailed);
+                assert!(reason.is_fatal());
             }
-            _ => panic!(),
+            other => panic!("expected a fatal close, got {:?}", other),
         }
     }
-    pub async fn get_channel_message(
-        &mut self,
-        chan: &str,
-        msg: &str,
-    ) -> crate::discordmessage::Message {
-        let msg = &self
-            .client
-            .get(&format!(
-                "https://discordapp.com/api/v6/channels/{}/messages/{}",
-                chan, msg
-            ))
-            .header("Authorization", &self.auth_header)
-            .send()
-            .await
-            .unwrap()
-            .text()
-            .await
+
+    #[test]
+    fn session_starts_wait_for_reset_once_the_reserve_is_reached() {
+        let mut starts = SessionStarts::default();
+        assert_eq!(starts.take(), None);
+        starts.update(&SessionStartLimit {
+            total: 1000,
+            remaining: SESSION_START_RESERVE + 1,
+            reset_after: 60_000,
+            max_concurrency: None,
+        });
+        assert_eq!(starts.take(), None);
+        let wait = starts.take().expect("identified into the reserve");
+        assert!(wait > Duration::from_secs(59), "{:?}", wait);
+        assert_eq!(starts.remaining, None);
+    }
+
+    #[tokio::test]
+    async fn session_starts_are_fetched_again_after_a_reset() {
+        let rest = crate::discordmock::MockRest::start().await;
+        let mut body = crate::discordmock::gateway_bot("wss://gateway.test");
+        body["session_start_limit"]["remaining"] = json!(SESSION_START_RESERVE + 1);
+        body["session_start_limit"]["reset_after"] = json!(100);
+        rest.respond("GET", "/api/v6/gateway/bot", 200, body);
+        let body = crate::discordmock::gateway_bot("wss://gateway.test");
+        rest.respond("GET", "/api/v6/gateway/bot", 200, body);
+        let mut client = DiscordClient::new(crate::discordmock::mock_http(&rest));
+
+        client.send_identify().await;
+        assert_eq!(
+            client.sessions.lock().unwrap().remaining,
+            Some(SESSION_START_RESERVE)
+        );
+        // into the reserve: waits out the reset, then the count is unknown
+        let start = tokio::time::Instant::now();
+        client.send_identify().await;
+        assert!(start.elapsed() >= Duration::from_millis(90));
+        client.send_identify().await;
+        assert_eq!(rest.requests().len(), 2);
+        assert_eq!(client.sessions.lock().unwrap().remaining, Some(999));
+    }
+
+    /// Compresses `data` onto `stream` with a sync flush, the way Discord
+    /// frames zlib-stream payloads.
+    fn deflate(stream: &mut flate2::Compress, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len() + 64);
+        stream
+            .compress_vec(data, &mut out, flate2::FlushCompress::Sync)
             .unwrap();
-        println!("get_chan_msg() -> {}", msg);
-        serde_json::from_str::<crate::discordmessage::Message>(msg).unwrap()
-    }
-    pub async fn create_reaction(&mut self, chan: &str, msg: &str, emoji: &str) {
-        let msg = &self
-            .client
-            .put(&format!(
-                "https://discordapp.com/api/v6/channels/{}/messages/{}/reactions/{}/@me",
-                chan, msg, emoji
-            ))
-            .header("Content-length", "0")
-            .header("Authorization", &self.auth_header)
-            .send()
-            .await
-            .unwrap()
-            .text()
-            .await
+        assert!(out.ends_with(ZLIB_SUFFIX));
+        out
+    }
+
+    #[test]
+    fn zlib_stream_payloads_split_across_frames_are_buffered() {
+        let mut stream = flate2::Compress::new(flate2::Compression::default(), true);
+        let payload = hello(41250).to_string();
+        let frame = deflate(&mut stream, payload.as_bytes());
+        let (head, tail) = frame.split_at(frame.len() / 2);
+
+        let mut inflater = Inflater::new(true);
+        assert_eq!(inflater.push(head), Ok(None));
+        assert_eq!(inflater.push(tail), Ok(Some(payload.into_bytes())));
+    }
+
+    #[test]
+    fn zlib_stream_carries_state_between_payloads() {
+        let mut stream = flate2::Compress::new(flate2::Compression::default(), true);
+        let mut inflater = Inflater::new(true);
+        // later payloads refer back to the earlier ones, so each needs the
+        // shared dictionary to inflate
+        for s in 1..4 {
+            let payload = dispatch("TYPING_START", s, json!({ "channel_id": "1" })).to_string();
+            let frame = deflate(&mut stream, payload.as_bytes());
+            assert_eq!(inflater.push(&frame), Ok(Some(payload.into_bytes())));
+        }
+    }
+
+    #[test]
+    fn compressed_payloads_inflate_frame_by_frame() {
+        let mut inflater = Inflater::new(false);
+        for s in 1..3 {
+            // identify `compress` deflates every payload on its own
+            let mut stream = flate2::Compress::new(flate2::Compression::default(), true);
+            let payload = ready(s).to_string();
+            let mut frame = Vec::with_capacity(payload.len() + 64);
+            stream
+                .compress_vec(
+                    payload.as_bytes(),
+                    &mut frame,
+                    flate2::FlushCompress::Finish,
+                )
+                .unwrap();
+            assert_eq!(inflater.push(&frame), Ok(Some(payload.into_bytes())));
+        }
+    }
+
+    #[test]
+    fn bytes_after_a_finished_stream_are_an_error() {
+        let payload = ready(1).to_string();
+        let mut stream = flate2::Compress::new(flate2::Compression::default(), true);
+        let mut frame = Vec::with_capacity(payload.len() + 64);
+        stream
+            .compress_vec(
+                payload.as_bytes(),
+                &mut frame,
+                flate2::FlushCompress::Finish,
+            )
             .unwrap();
-        println!("create_reaction() -> {}", msg);
+
+        let mut trailing = frame.clone();
+        trailing.extend_from_slice(b"xx");
+        assert!(Inflater::new(false).push(&trailing).is_err());
+
+        // a zlib-stream connection must not end its stream mid-session
+        frame.extend_from_slice(ZLIB_SUFFIX);
+        assert!(Inflater::new(true).push(&frame).is_err());
     }
 }
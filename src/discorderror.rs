@@ -0,0 +1,234 @@
+use std::time::Duration;
+
+#[derive(Debug)]
+pub enum DiscordError {
+    /// A REST request could not be sent or its response could not be read.
+    Http(reqwest::Error),
+    /// The gateway websocket could not be opened or failed mid-stream.
+    Gateway(tungstenite::Error),
+    /// Discord rejected a request with a JSON error body, e.g. code 50001
+    /// "Missing Access" alongside a 403.
+    Api {
+        status: reqwest::StatusCode,
+        code: u32,
+        message: String,
+    },
+    /// Discord rejected a request without a recognizable error body.
+    Status {
+        status: reqwest::StatusCode,
+        body: String,
+    },
+    /// A request was refused with 429; it may be retried after `retry_after`.
+    RateLimited { retry_after: Duration, global: bool },
+    /// A REST response or gateway payload could not be decoded.
+    Decode(String),
+    /// The gateway closed the session for a reason reconnecting can't fix,
+    /// such as a bad token or intents the bot may not use.
+    GatewayClosed {
+        reason: CloseReason,
+        message: String,
+    },
+    /// Too few of the day's session starts are left to bring the bot up.
+    SessionStartLimit {
+        remaining: u32,
+        reset_after: Duration,
+    },
+    /// Part of an embed is over one of Discord's limits, e.g. more than 25
+    /// fields. `len` and `max` count characters, or fields for "fields".
+    EmbedLimit {
+        what: &'static str,
+        len: usize,
+        max: usize,
+    },
+    /// A gateway request went unanswered, e.g. Request Guild Members whose
+    /// last chunk never arrived.
+    Timeout { what: &'static str, after: Duration },
+}
+
+/// Why the gateway closed a connection, from the close frame's code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    UnknownError,
+    UnknownOpcode,
+    DecodeError,
+    NotAuthenticated,
+    AuthenticationFailed,
+    AlreadyAuthenticated,
+    InvalidSeq,
+    RateLimited,
+    SessionTimedOut,
+    InvalidShard,
+    ShardingRequired,
+    InvalidApiVersion,
+    InvalidIntents,
+    DisallowedIntents,
+    Other(u16),
+}
+
+impl CloseReason {
+    pub fn from_code(code: u16) -> CloseReason {
+        match code {
+            4000 => CloseReason::UnknownError,
+            4001 => CloseReason::UnknownOpcode,
+            4002 => CloseReason::DecodeError,
+            4003 => CloseReason::NotAuthenticated,
+            4004 => CloseReason::AuthenticationFailed,
+            4005 => CloseReason::AlreadyAuthenticated,
+            4007 => CloseReason::InvalidSeq,
+            4008 => CloseReason::RateLimited,
+            4009 => CloseReason::SessionTimedOut,
+            4010 => CloseReason::InvalidShard,
+            4011 => CloseReason::ShardingRequired,
+            4012 => CloseReason::InvalidApiVersion,
+            4013 => CloseReason::InvalidIntents,
+            4014 => CloseReason::DisallowedIntents,
+            code => CloseReason::Other(code),
+        }
+    }
+    pub fn code(self) -> u16 {
+        match self {
+            CloseReason::UnknownError => 4000,
+            CloseReason::UnknownOpcode => 4001,
+            CloseReason::DecodeError => 4002,
+            CloseReason::NotAuthenticated => 4003,
+            CloseReason::AuthenticationFailed => 4004,
+            CloseReason::AlreadyAuthenticated => 4005,
+            CloseReason::InvalidSeq => 4007,
+            CloseReason::RateLimited => 4008,
+            CloseReason::SessionTimedOut => 4009,
+            CloseReason::InvalidShard => 4010,
+            CloseReason::ShardingRequired => 4011,
+            CloseReason::InvalidApiVersion => 4012,
+            CloseReason::InvalidIntents => 4013,
+            CloseReason::DisallowedIntents => 4014,
+            CloseReason::Other(code) => code,
+        }
+    }
+    /// Reconnecting would only be closed again the same way; the bot's
+    /// configuration has to change.
+    pub fn is_fatal(self) -> bool {
+        matches!(
+            self,
+            CloseReason::AuthenticationFailed
+                | CloseReason::InvalidShard
+                | CloseReason::ShardingRequired
+                | CloseReason::InvalidApiVersion
+                | CloseReason::InvalidIntents
+                | CloseReason::DisallowedIntents
+        )
+    }
+    /// The session can't be resumed and the next connection has to identify.
+    pub fn invalidates_session(self) -> bool {
+        matches!(
+            self,
+            CloseReason::NotAuthenticated | CloseReason::InvalidSeq | CloseReason::SessionTimedOut
+        )
+    }
+}
+
+impl std::fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let what = match self {
+            CloseReason::UnknownError => "unknown error",
+            CloseReason::UnknownOpcode => "unknown opcode",
+            CloseReason::DecodeError => "payload could not be decoded",
+            CloseReason::NotAuthenticated => "payload sent before identifying",
+            CloseReason::AuthenticationFailed => "authentication failed, check the bot token",
+            CloseReason::AlreadyAuthenticated => "identified more than once",
+            CloseReason::InvalidSeq => "invalid sequence number on resume",
+            CloseReason::RateLimited => "payloads sent too quickly",
+            CloseReason::SessionTimedOut => "session timed out",
+            CloseReason::InvalidShard => "invalid shard",
+            CloseReason::ShardingRequired => "too many guilds, sharding required",
+            CloseReason::InvalidApiVersion => "invalid gateway API version",
+            CloseReason::InvalidIntents => "invalid intents",
+            CloseReason::DisallowedIntents => {
+                "intents not enabled or not allowed for this bot in the developer portal"
+            }
+            CloseReason::Other(_) => "unrecognized close code",
+        };
+        write!(f, "{} ({})", what, self.code())
+    }
+}
+
+pub type DiscordResult<T> = Result<T, DiscordError>;
+
+impl std::fmt::Display for DiscordError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DiscordError::Http(e) => write!(f, "HTTP request failed: {}", e),
+            DiscordError::Gateway(e) => write!(f, "gateway connection failed: {}", e),
+            DiscordError::Api {
+                status,
+                code,
+                message,
+            } => write!(f, "Discord error {} ({}): {}", code, status, message),
+            DiscordError::Status { status, body } => {
+                write!(f, "unexpected HTTP status {}: {}", status, body)
+            }
+            DiscordError::RateLimited {
+                retry_after,
+                global,
+            } => write!(
+                f,
+                "{}rate limited, retry after {:?}",
+                if *global { "globally " } else { "" },
+                retry_after
+            ),
+            DiscordError::Decode(e) => write!(f, "could not decode payload: {}", e),
+            DiscordError::GatewayClosed { reason, message } => {
+                write!(f, "gateway closed the session: {}", reason)?;
+                if !message.is_empty() {
+                    write!(f, ": {}", message)?;
+                }
+                Ok(())
+            }
+            DiscordError::SessionStartLimit {
+                remaining,
+                reset_after,
+            } => write!(
+                f,
+                "only {} session starts left today, refusing to identify until they reset in {:?}",
+                remaining, reset_after
+            ),
+            DiscordError::EmbedLimit { what, len, max } => {
+                write!(
+                    f,
+                    "embed {} is {} long, over the limit of {}",
+                    what, len, max
+                )
+            }
+            DiscordError::Timeout { what, after } => {
+                write!(f, "{} went unanswered for {:?}", what, after)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DiscordError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DiscordError::Http(e) => Some(e),
+            DiscordError::Gateway(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for DiscordError {
+    fn from(e: reqwest::Error) -> Self {
+        DiscordError::Http(e)
+    }
+}
+
+impl From<tungstenite::Error> for DiscordError {
+    fn from(e: tungstenite::Error) -> Self {
+        DiscordError::Gateway(e)
+    }
+}
+
+impl From<serde_json::Error> for DiscordError {
+    fn from(e: serde_json::Error) -> Self {
+        DiscordError::Decode(e.to_string())
+    }
+}
@@ -0,0 +1,370 @@
+//! Erlang External Term Format, the gateway's `encoding=etf` alternative to
+//! JSON. Terms are converted to and from `serde_json::Value` so both encodings
+//! go through the same `DiscordMessage` deserializer.
+
+use serde_json::{Map, Number, Value};
+use std::io::Read;
+
+const VERSION: u8 = 131;
+const NEW_FLOAT_EXT: u8 = 70;
+const COMPRESSED: u8 = 80;
+const SMALL_INTEGER_EXT: u8 = 97;
+const INTEGER_EXT: u8 = 98;
+const FLOAT_EXT: u8 = 99;
+const ATOM_EXT: u8 = 100;
+const SMALL_TUPLE_EXT: u8 = 104;
+const LARGE_TUPLE_EXT: u8 = 105;
+const NIL_EXT: u8 = 106;
+const STRING_EXT: u8 = 107;
+const LIST_EXT: u8 = 108;
+const BINARY_EXT: u8 = 109;
+const SMALL_BIG_EXT: u8 = 110;
+const LARGE_BIG_EXT: u8 = 111;
+const SMALL_ATOM_EXT: u8 = 115;
+const MAP_EXT: u8 = 116;
+const ATOM_UTF8_EXT: u8 = 118;
+const SMALL_ATOM_UTF8_EXT: u8 = 119;
+
+/// Encodes a payload the way Discord expects to receive it: null and booleans
+/// as atoms, strings and map keys as binaries.
+pub fn encode(value: &Value) -> Vec<u8> {
+    let mut out = vec![VERSION];
+    encode_term(value, &mut out);
+    out
+}
+
+fn encode_term(value: &Value, out: &mut Vec<u8>) {
+    match value {
+        Value::Null => encode_atom("nil", out),
+        Value::Bool(b) => encode_atom(if *b { "true" } else { "false" }, out),
+        Value::Number(n) => {
+            if let Some(i) = n.as_i64() {
+                if (0..=255).contains(&i) {
+                    out.push(SMALL_INTEGER_EXT);
+                    out.push(i as u8);
+                } else if i >= i64::from(i32::MIN) && i <= i64::from(i32::MAX) {
+                    out.push(INTEGER_EXT);
+                    out.extend_from_slice(&(i as i32).to_be_bytes());
+                } else {
+                    encode_big(i.unsigned_abs(), i < 0, out);
+                }
+            } else if let Some(u) = n.as_u64() {
+                encode_big(u, false, out);
+            } else {
+                out.push(NEW_FLOAT_EXT);
+                out.extend_from_slice(&n.as_f64().unwrap_or(0.0).to_be_bytes());
+            }
+        }
+        Value::String(s) => encode_binary(s, out),
+        Value::Array(items) => {
+            if !items.is_empty() {
+                out.push(LIST_EXT);
+                out.extend_from_slice(&(items.len() as u32).to_be_bytes());
+                for item in items {
+                    encode_term(item, out);
+                }
+            }
+            out.push(NIL_EXT);
+        }
+        Value::Object(map) => {
+            out.push(MAP_EXT);
+            out.extend_from_slice(&(map.len() as u32).to_be_bytes());
+            for (k, v) in map {
+                encode_binary(k, out);
+                encode_term(v, out);
+            }
+        }
+    }
+}
+
+fn encode_atom(name: &str, out: &mut Vec<u8>) {
+    out.push(SMALL_ATOM_UTF8_EXT);
+    out.push(name.len() as u8);
+    out.extend_from_slice(name.as_bytes());
+}
+
+fn encode_binary(s: &str, out: &mut Vec<u8>) {
+    out.push(BINARY_EXT);
+    out.extend_from_slice(&(s.len() as u32).to_be_bytes());
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn encode_big(mut v: u64, negative: bool, out: &mut Vec<u8>) {
+    let mut digits = vec![];
+    while v > 0 {
+        digits.push(v as u8);
+        v >>= 8;
+    }
+    out.push(SMALL_BIG_EXT);
+    out.push(digits.len() as u8);
+    out.push(negative as u8);
+    out.extend_from_slice(&digits);
+}
+
+/// Decodes a gateway payload. Atoms become null, booleans or strings, tuples
+/// and lists become arrays, and big integers (how Discord sends snowflakes)
+/// become decimal strings so they match the JSON encoding.
+pub fn decode(data: &[u8]) -> Result<Value, String> {
+    let mut decoder = Decoder { data, pos: 0 };
+    let version = decoder.u8()?;
+    if version != VERSION {
+        return Err(format!("unsupported ETF version {}", version));
+    }
+    let value = decoder.term()?;
+    if decoder.pos != data.len() {
+        return Err(format!(
+            "{} trailing bytes after ETF term",
+            data.len() - decoder.pos
+        ));
+    }
+    Ok(value)
+}
+
+struct Decoder<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if self.data.len() - self.pos < n {
+            return Err(format!("ETF term truncated at byte {}", self.pos));
+        }
+        let bytes = &self.data[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+    fn u16(&mut self) -> Result<u16, String> {
+        let b = self.take(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+    fn u32(&mut self) -> Result<u32, String> {
+        let b = self.take(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+    fn string(&mut self, n: usize) -> Result<String, String> {
+        let bytes = self.take(n)?;
+        String::from_utf8(bytes.to_vec()).map_err(|e| e.to_string())
+    }
+    fn terms(&mut self, n: usize) -> Result<Vec<Value>, String> {
+        (0..n).map(|_| self.term()).collect()
+    }
+
+    fn term(&mut self) -> Result<Value, String> {
+        match self.u8()? {
+            SMALL_INTEGER_EXT => Ok(Value::from(self.u8()?)),
+            INTEGER_EXT => Ok(Value::from(self.u32()? as i32)),
+            NEW_FLOAT_EXT => {
+                let b = self.take(8)?;
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(b);
+                float(f64::from_be_bytes(bytes))
+            }
+            FLOAT_EXT => {
+                let text = self.string(31)?;
+                let v = text
+                    .trim_end_matches('\0')
+                    .parse()
+                    .map_err(|e| format!("bad ETF float {:?}: {}", text, e))?;
+                float(v)
+            }
+            ATOM_EXT | ATOM_UTF8_EXT => {
+                let n = self.u16()? as usize;
+                Ok(atom(self.string(n)?))
+            }
+            SMALL_ATOM_EXT | SMALL_ATOM_UTF8_EXT => {
+                let n = self.u8()? as usize;
+                Ok(atom(self.string(n)?))
+            }
+            SMALL_TUPLE_EXT => {
+                let n = self.u8()? as usize;
+                Ok(Value::Array(self.terms(n)?))
+            }
+            LARGE_TUPLE_EXT => {
+                let n = self.u32()? as usize;
+                Ok(Value::Array(self.terms(n)?))
+            }
+            NIL_EXT => Ok(Value::Array(vec![])),
+            STRING_EXT => {
+                // a list of small integers, not text
+                let n = self.u16()? as usize;
+                Ok(Value::Array(
+                    self.take(n)?.iter().map(|&b| Value::from(b)).collect(),
+                ))
+            }
+            LIST_EXT => {
+                let n = self.u32()? as usize;
+                let items = self.terms(n)?;
+                match self.term()? {
+                    Value::Array(ref tail) if tail.is_empty() => Ok(Value::Array(items)),
+                    tail => Err(format!("improper ETF list with tail {}", tail)),
+                }
+            }
+            BINARY_EXT => {
+                let n = self.u32()? as usize;
+                Ok(Value::String(self.string(n)?))
+            }
+            SMALL_BIG_EXT => {
+                let n = self.u8()? as usize;
+                self.big(n)
+            }
+            LARGE_BIG_EXT => {
+                let n = self.u32()? as usize;
+                self.big(n)
+            }
+            MAP_EXT => {
+                let n = self.u32()? as usize;
+                let mut map = Map::new();
+                for _ in 0..n {
+                    let key = match self.term()? {
+                        Value::String(s) => s,
+                        Value::Null => "nil".to_string(),
+                        key @ Value::Number(_) | key @ Value::Bool(_) => key.to_string(),
+                        key => return Err(format!("unsupported ETF map key {}", key)),
+                    };
+                    map.insert(key, self.term()?);
+                }
+                Ok(Value::Object(map))
+            }
+            COMPRESSED => {
+                let size = self.u32()? as usize;
+                let mut inflated = Vec::with_capacity(size);
+                flate2::read::ZlibDecoder::new(&self.data[self.pos..])
+                    .read_to_end(&mut inflated)
+                    .map_err(|e| e.to_string())?;
+                if inflated.len() != size {
+                    return Err(format!(
+                        "compressed ETF term is {} bytes, expected {}",
+                        inflated.len(),
+                        size
+                    ));
+                }
+                self.pos = self.data.len();
+                let mut inner = Decoder {
+                    data: &inflated,
+                    pos: 0,
+                };
+                inner.term()
+            }
+            tag => Err(format!(
+                "unsupported ETF tag {} at byte {}",
+                tag,
+                self.pos - 1
+            )),
+        }
+    }
+    fn big(&mut self, n: usize) -> Result<Value, String> {
+        let sign = self.u8()?;
+        if n > 8 {
+            return Err(format!("{}-byte ETF integer does not fit in 64 bits", n));
+        }
+        let v = self
+            .take(n)?
+            .iter()
+            .rev()
+            .fold(0u64, |acc, &b| (acc << 8) | u64::from(b));
+        Ok(Value::String(if sign == 0 {
+            v.to_string()
+        } else {
+            format!("-{}", v)
+        }))
+    }
+}
+
+fn atom(name: String) -> Value {
+    match name.as_str() {
+        "nil" => Value::Null,
+        "true" => Value::Bool(true),
+        "false" => Value::Bool(false),
+        _ => Value::String(name),
+    }
+}
+
+fn float(v: f64) -> Result<Value, String> {
+    Number::from_f64(v)
+        .map(Value::Number)
+        .ok_or_else(|| format!("ETF float {} has no JSON representation", v))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::discordclient::Encoding;
+
+    /// Gateway payloads as Discord sends them over JSON. Every fixture must
+    /// decode to the same `DiscordMessage` through both encodings.
+    const FIXTURES: &[&str] = &[
+        r#"{"t":null,"s":null,"op":10,"d":{"heartbeat_interval":41250,"_trace":["gateway-prd-main-8x2j"]}}"#,
+        r#"{"t":null,"s":null,"op":11,"d":null}"#,
+        r#"{"t":null,"s":null,"op":1,"d":null}"#,
+        r#"{"t":null,"s":null,"op":7,"d":null}"#,
+        r#"{"t":null,"s":null,"op":9,"d":false}"#,
+        r#"{"t":"READY","s":1,"op":0,"d":{"v":6,"user":{"id":"80351110224678912","username":"whois","discriminator":"1337","bot":true},"session_id":"b0a2c4d6e8f0","guilds":[{"id":"41771983423143937","unavailable":true}],"shard":[0,1]}}"#,
+        r#"{"t":"RESUMED","s":42,"op":0,"d":{"v":6,"session_id":"b0a2c4d6e8f0"}}"#,
+        r#"{"t":"GUILD_CREATE","s":2,"op":0,"d":{"id":"41771983423143937","name":"Test Guild","owner_id":"80351110224678912","permissions":2147483647,"members":[{"user":{"id":"80351110224678912","username":"whois"},"nick":null,"roles":["41771983423143936"]}],"channels":[{"id":"41771983423143938","type":0,"guild_id":"41771983423143937","last_message_id":"698563445125464094","name":"bot-playground","topic":null}]}}"#,
+        r#"{"t":"MESSAGE_CREATE","s":3,"op":0,"d":{"id":"698563445125464094","channel_id":"41771983423143938","guild_id":"41771983423143937","author":{"id":"53908232506183680","username":"Mason","discriminator":"9999"},"content":"ras0219++","timestamp":"2020-04-12T01:02:03.456000+00:00","edited_timestamp":null,"tts":false,"reactions":[{"count":2,"me":true,"emoji":{"id":null,"name":"❤"}}]}}"#,
+        r#"{"t":"PRESENCE_UPDATE","s":4,"op":0,"d":{"user":{"id":"53908232506183680"},"game":{"name":"Factorio","type":0,"created_at":1586653323456},"guild_id":"41771983423143937","client_status":{"desktop":"online"},"nick":"mason"}}"#,
+        r#"{"t":"TYPING_START","s":5,"op":0,"d":{"user_id":"53908232506183680","channel_id":"41771983423143938","timestamp":1586653323}}"#,
+    ];
+
+    #[test]
+    fn fixtures_match_across_encodings() {
+        for fixture in FIXTURES {
+            let json = Encoding::Json.decode(fixture.as_bytes()).unwrap();
+            let value: Value = serde_json::from_str(fixture).unwrap();
+            let etf = Encoding::Etf.decode(&encode(&value)).unwrap();
+            assert_eq!(format!("{:?}", json), format!("{:?}", etf), "{}", fixture);
+        }
+    }
+
+    #[test]
+    fn decodes_erlang_terms() {
+        // #{d => nil, op => 11, s => nil, t => nil}, as term_to_binary/1 lays it out
+        let ack = [
+            131, 116, 0, 0, 0, 4, 100, 0, 1, b'd', 100, 0, 3, b'n', b'i', b'l', 100, 0, 2, b'o',
+            b'p', 97, 11, 100, 0, 1, b's', 100, 0, 3, b'n', b'i', b'l', 100, 0, 1, b't', 100, 0, 3,
+            b'n', b'i', b'l',
+        ];
+        match Encoding::Etf.decode(&ack).unwrap() {
+            crate::discordmessage::DiscordMessage::HeartbeatAck {} => {}
+            other => panic!("expected HeartbeatAck, got {:?}", other),
+        }
+
+        // {[0, 1], 80351110224678912, -1}: a byte list, a snowflake and a negative integer
+        let term = [
+            131, 104, 3, 107, 0, 2, 0, 1, 110, 8, 0, 0x00, 0x10, 0x40, 0xb6, 0xe8, 0x76, 0x1d,
+            0x01, 98, 0xff, 0xff, 0xff, 0xff,
+        ];
+        assert_eq!(
+            decode(&term).unwrap(),
+            serde_json::json!([[0, 1], "80351110224678912", -1])
+        );
+    }
+
+    #[test]
+    fn round_trips_outgoing_payloads() {
+        let payload = serde_json::json!({
+            "op": 2,
+            "d": {
+                "token": "abc",
+                "intents": 771,
+                "shard": [3, 16],
+                "compress": false,
+                "presence": { "since": null, "afk": false, "game": { "name": "%help", "type": 0 } },
+                "ratio": 0.5
+            }
+        });
+        assert_eq!(decode(&encode(&payload)).unwrap(), payload);
+    }
+
+    #[test]
+    fn rejects_malformed_terms() {
+        assert!(decode(&[]).is_err());
+        assert!(decode(&[130, 106]).is_err());
+        assert!(decode(&[131, 109, 0, 0, 0, 5, b'a']).is_err());
+        assert!(decode(&[131, 106, 106]).is_err());
+    }
+}
@@ -0,0 +1,1014 @@
+use crate::discorderror::*;
+use crate::discordmessage::*;
+use crate::discordratelimit::*;
+use futures_util::stream::BoxStream;
+use serde::de::DeserializeOwned;
+use serde_json::json;
+use std::sync::Arc;
+
+const API_URL: &str = "https://discordapp.com/api";
+pub const API_VERSION: u32 = 6;
+/// Attempts made for a REST call that keeps failing with a 5xx, a 429 or a
+/// dropped connection.
+const REST_ATTEMPTS: u32 = 3;
+/// Milliseconds since the Unix epoch at the start of 2015, where snowflake
+/// timestamps count from.
+const DISCORD_EPOCH: u64 = 1_420_070_400_000;
+/// Bulk delete refuses messages older than two weeks; leave a minute to spare.
+const BULK_DELETE_MAX_AGE: std::time::Duration =
+    std::time::Duration::from_secs(14 * 24 * 60 * 60 - 60);
+
+/// A REST call relative to the API base, e.g.
+/// `Request::get(format!("/channels/{}", id))`. Endpoint methods describe the
+/// call and `Http::request` takes care of sending it.
+struct Request {
+    method: reqwest::Method,
+    path: String,
+    body: Option<serde_json::Value>,
+    reason: Option<String>,
+    /// Files to upload by name. With any, the request is sent as
+    /// multipart/form-data with the JSON body as `payload_json`.
+    files: Vec<(String, Vec<u8>)>,
+}
+
+impl Request {
+    fn new(method: reqwest::Method, path: String) -> Request {
+        Request {
+            method,
+            path,
+            body: None,
+            reason: None,
+            files: vec![],
+        }
+    }
+    fn get(path: String) -> Request {
+        Request::new(reqwest::Method::GET, path)
+    }
+    fn post(path: String) -> Request {
+        Request::new(reqwest::Method::POST, path)
+    }
+    fn put(path: String) -> Request {
+        Request::new(reqwest::Method::PUT, path)
+    }
+    fn patch(path: String) -> Request {
+        Request::new(reqwest::Method::PATCH, path)
+    }
+    fn delete(path: String) -> Request {
+        Request::new(reqwest::Method::DELETE, path)
+    }
+    fn json(mut self, body: serde_json::Value) -> Request {
+        self.body = Some(body);
+        self
+    }
+    fn files(mut self, files: Vec<(String, Vec<u8>)>) -> Request {
+        self.files = files;
+        self
+    }
+    /// Records `reason` in the guild's audit log entry for this call.
+    fn reason(mut self, reason: &str) -> Request {
+        self.reason = Some(reason.to_string());
+        self
+    }
+}
+
+/// Percent-encodes everything but unreserved characters, since header values
+/// must be ASCII and Discord decodes the audit log reason.
+fn percent_encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || b"-_.~ ".contains(&b) {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+    }
+    out
+}
+
+/// Which mentions in a message may ping, e.g. `AllowedMentions::none()` to
+/// repeat user input without pinging anyone.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AllowedMentions {
+    parse: Vec<&'static str>,
+    users: Vec<String>,
+    roles: Vec<String>,
+    replied_user: bool,
+}
+
+impl AllowedMentions {
+    pub fn none() -> AllowedMentions {
+        AllowedMentions::default()
+    }
+    /// Lets every mentioned user be pinged.
+    pub fn all_users(mut self) -> AllowedMentions {
+        self.parse.push("users");
+        self
+    }
+    /// Lets every mentioned role be pinged.
+    pub fn all_roles(mut self) -> AllowedMentions {
+        self.parse.push("roles");
+        self
+    }
+    /// Lets @everyone and @here ping.
+    pub fn everyone(mut self) -> AllowedMentions {
+        self.parse.push("everyone");
+        self
+    }
+    pub fn user(mut self, id: &str) -> AllowedMentions {
+        self.users.push(id.to_string());
+        self
+    }
+    pub fn role(mut self, id: &str) -> AllowedMentions {
+        self.roles.push(id.to_string());
+        self
+    }
+    /// Pings the author of the message being replied to.
+    pub fn replied_user(mut self, ping: bool) -> AllowedMentions {
+        self.replied_user = ping;
+        self
+    }
+    fn payload(&self) -> serde_json::Value {
+        json!({
+            "parse": self.parse,
+            "users": self.users,
+            "roles": self.roles,
+            "replied_user": self.replied_user
+        })
+    }
+}
+
+/// A message to send with `Http::create_msg`, e.g.
+/// `CreateMessage::new().content("hi").reply_to(&msg.id)`. Plain text
+/// converts from `&str`. Unset fields are left out of the request.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CreateMessage {
+    content: Option<String>,
+    embed: Option<Embed>,
+    reply_to: Option<String>,
+    allowed_mentions: Option<AllowedMentions>,
+    tts: bool,
+    nonce: Option<String>,
+    files: Vec<(String, Vec<u8>)>,
+}
+
+impl CreateMessage {
+    pub fn new() -> CreateMessage {
+        CreateMessage::default()
+    }
+    pub fn content(mut self, content: &str) -> CreateMessage {
+        self.content = Some(content.to_string());
+        self
+    }
+    pub fn embed(mut self, embed: Embed) -> CreateMessage {
+        self.embed = Some(embed);
+        self
+    }
+    /// Sends the message as a reply to message `id` in the same channel.
+    pub fn reply_to(mut self, id: &str) -> CreateMessage {
+        self.reply_to = Some(id.to_string());
+        self
+    }
+    pub fn allowed_mentions(mut self, allowed: AllowedMentions) -> CreateMessage {
+        self.allowed_mentions = Some(allowed);
+        self
+    }
+    pub fn tts(mut self, tts: bool) -> CreateMessage {
+        self.tts = tts;
+        self
+    }
+    /// Attaches a file, e.g. `file("karma.csv", csv.into_bytes())`. Content
+    /// may be left out of messages with files.
+    pub fn file(mut self, filename: &str, data: Vec<u8>) -> CreateMessage {
+        self.files.push((filename.to_string(), data));
+        self
+    }
+    /// Echoed back in the MESSAGE_CREATE event, to recognize the message.
+    pub fn nonce(mut self, nonce: &str) -> CreateMessage {
+        self.nonce = Some(nonce.to_string());
+        self
+    }
+    fn payload(&self) -> serde_json::Value {
+        let mut body = json!({});
+        if let Some(content) = &self.content {
+            body["content"] = json!(content);
+        }
+        if let Some(embed) = &self.embed {
+            body["embed"] = json!(embed);
+        }
+        if let Some(id) = &self.reply_to {
+            body["message_reference"] = json!({ "message_id": id });
+        }
+        if let Some(allowed) = &self.allowed_mentions {
+            body["allowed_mentions"] = allowed.payload();
+        }
+        if self.tts {
+            body["tts"] = json!(true);
+        }
+        if let Some(nonce) = &self.nonce {
+            body["nonce"] = json!(nonce);
+        }
+        body
+    }
+}
+
+impl From<&str> for CreateMessage {
+    fn from(content: &str) -> CreateMessage {
+        CreateMessage::new().content(content)
+    }
+}
+
+impl From<&String> for CreateMessage {
+    fn from(content: &String) -> CreateMessage {
+        CreateMessage::new().content(content)
+    }
+}
+
+/// Changes to make to a sent message with `Http::edit_msg`. Unset fields
+/// are left as they are.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EditMessage {
+    content: Option<String>,
+    embed: Option<Option<Embed>>,
+}
+
+impl EditMessage {
+    pub fn new() -> EditMessage {
+        EditMessage::default()
+    }
+    pub fn content(mut self, content: &str) -> EditMessage {
+        self.content = Some(content.to_string());
+        self
+    }
+    pub fn embed(mut self, embed: Embed) -> EditMessage {
+        self.embed = Some(Some(embed));
+        self
+    }
+    pub fn remove_embed(mut self) -> EditMessage {
+        self.embed = Some(None);
+        self
+    }
+    fn payload(&self) -> serde_json::Value {
+        let mut body = json!({});
+        if let Some(content) = &self.content {
+            body["content"] = json!(content);
+        }
+        if let Some(embed) = &self.embed {
+            body["embed"] = json!(embed);
+        }
+        body
+    }
+}
+
+/// Where to start reading a channel's history with `Http::history`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum History {
+    /// From the newest message back.
+    Latest,
+    /// Back from before message `id`, newest first.
+    Before(String),
+    /// Forward from after message `id`, oldest first. `After("0")` walks the
+    /// whole channel from its first message.
+    After(String),
+    /// The hundred or so messages around message `id`, newest first. This
+    /// is a single page.
+    Around(String),
+}
+
+/// Messages fetched per page of history, the most Discord allows.
+const HISTORY_PAGE: usize = 100;
+
+/// Progress of a history stream between pages.
+struct HistoryCursor {
+    http: Http,
+    chan_id: String,
+    next: Option<History>,
+    page: std::collections::VecDeque<Message>,
+}
+
+impl HistoryCursor {
+    async fn next_page(&mut self) -> DiscordResult<()> {
+        let position = match self.next.take() {
+            Some(position) => position,
+            None => return Ok(()),
+        };
+        let query = match &position {
+            History::Latest => String::new(),
+            History::Before(id) => format!("&before={}", id),
+            History::After(id) => format!("&after={}", id),
+            History::Around(id) => format!("&around={}", id),
+        };
+        let req = Request::get(format!(
+            "/channels/{}/messages?limit={}{}",
+            self.chan_id, HISTORY_PAGE, query
+        ));
+        let mut page: Vec<Message> = self.http.request(req).await?;
+        let full = page.len() == HISTORY_PAGE;
+        let id = |m: &Message| m.id.parse::<u64>().unwrap_or(0);
+        if let History::After(_) = position {
+            page.sort_by_key(id);
+        } else {
+            page.sort_by_key(|m| std::cmp::Reverse(id(m)));
+        }
+        self.next = match (position, page.last()) {
+            (History::Around(_), _) | (_, None) => None,
+            _ if !full => None,
+            (History::After(_), Some(last)) => Some(History::After(last.id.clone())),
+            (_, Some(last)) => Some(History::Before(last.id.clone())),
+        };
+        self.page.extend(page);
+        Ok(())
+    }
+}
+
+/// How long ago the message or other object with snowflake `id` was created.
+fn snowflake_age(id: &str) -> Option<std::time::Duration> {
+    let created = (id.parse::<u64>().ok()? >> 22) + DISCORD_EPOCH;
+    let created = std::time::UNIX_EPOCH + std::time::Duration::from_millis(created);
+    Some(created.elapsed().unwrap_or_default())
+}
+
+#[derive(Clone)]
+struct HttpInner {
+    token: String,
+    client: reqwest::Client,
+    api_base: String,
+    ratelimit: RateLimiter,
+    offline: bool,
+    auth_header: String,
+}
+
+/// Handle for Discord's REST API. Clones are cheap and share the connection
+/// pool and rate limits, so any number of tasks can make calls at once.
+/// Settings changed through one handle don't affect existing clones.
+#[derive(Clone)]
+pub struct Http {
+    inner: Arc<HttpInner>,
+}
+
+impl Http {
+    pub fn new(tok: &str) -> DiscordResult<Http> {
+        let client = reqwest::Client::builder()
+            .user_agent("DiscordBot (https://github.com/ras0219, 0)")
+            .build()?;
+        Ok(Http {
+            inner: Arc::new(HttpInner {
+                token: tok.to_string(),
+                client,
+                api_base: format!("{}/v{}", API_URL, API_VERSION),
+                ratelimit: RateLimiter::new(),
+                offline: false,
+                auth_header: format!("Bot {}", tok),
+            }),
+        })
+    }
+    pub fn token(&self) -> &str {
+        &self.inner.token
+    }
+    /// Points REST calls at another server, e.g. `http://127.0.0.1:8080/api`.
+    /// The API version is appended.
+    pub fn set_api_url(&mut self, url: &str) {
+        Arc::make_mut(&mut self.inner).api_base =
+            format!("{}/v{}", url.trim_end_matches('/'), API_VERSION);
+    }
+    /// While offline, REST calls are logged and answered with a stand-in
+    /// instead of being sent, e.g. while replaying a recording.
+    pub fn set_offline(&mut self, offline: bool) {
+        Arc::make_mut(&mut self.inner).offline = offline;
+    }
+    /// Shares this handle's rate limits with another handle for the same
+    /// token.
+    pub fn rate_limiter(&self) -> RateLimiter {
+        self.inner.ratelimit.clone()
+    }
+    pub fn set_rate_limiter(&mut self, ratelimit: RateLimiter) {
+        Arc::make_mut(&mut self.inner).ratelimit = ratelimit;
+    }
+    /// Sends a REST request once its rate limit bucket allows it, and records
+    /// the limits its response reports.
+    async fn execute(&self, req: reqwest::RequestBuilder) -> DiscordResult<Response> {
+        let req = req.build()?;
+        let route = Route::new(req.method(), req.url().path());
+        let ticket = self.inner.ratelimit.acquire(&route).await;
+        let res = self.inner.client.execute(req).await?;
+        let status = res.status();
+        let headers = res.headers().clone();
+        // a 429 may only say how long to wait in its body
+        let body = res.text().await;
+        self.inner.ratelimit.update(
+            ticket,
+            status,
+            &headers,
+            body.as_deref().unwrap_or_default(),
+        );
+        Ok(Response {
+            status,
+            headers,
+            body: body?,
+        })
+    }
+    /// Sends `req` and returns the body of its successful response. Server
+    /// errors and dropped connections are retried with backoff, and a 429 as
+    /// soon as the rate limiter lets the retry through.
+    async fn send_request(&self, req: Request) -> DiscordResult<String> {
+        let what = format!("{} {}", req.method, req.path);
+        if self.inner.offline {
+            println!(
+                "{} (offline) {}",
+                what,
+                req.body.clone().unwrap_or_default()
+            );
+            return Ok(offline_response(&req).to_string());
+        }
+        let mut backoff = std::time::Duration::from_millis(500);
+        let mut attempt = 1;
+        loop {
+            let mut builder = self
+                .inner
+                .client
+                .request(
+                    req.method.clone(),
+                    &format!("{}{}", self.inner.api_base, req.path),
+                )
+                .header("Authorization", &self.inner.auth_header);
+            builder = match &req.body {
+                _ if !req.files.is_empty() => builder.multipart(Self::form(&req)),
+                Some(body) => builder.json(body),
+                None => builder.header("Content-Length", "0"),
+            };
+            if let Some(reason) = &req.reason {
+                builder = builder.header("X-Audit-Log-Reason", percent_encode(reason));
+            }
+            match self.execute(builder).await {
+                Ok(res)
+                    if res.status == reqwest::StatusCode::TOO_MANY_REQUESTS
+                        && attempt < REST_ATTEMPTS =>
+                {
+                    println!("{} -> {}, retrying", what, res.status);
+                    attempt += 1;
+                    continue;
+                }
+                Ok(res) if res.status.is_server_error() && attempt < REST_ATTEMPTS => {
+                    println!("{} -> {}, retrying in {:?}", what, res.status, backoff);
+                }
+                Ok(res) => return Self::check_response(&what, res),
+                Err(DiscordError::Http(e))
+                    if (e.is_connect() || e.is_timeout()) && attempt < REST_ATTEMPTS =>
+                {
+                    println!("{} failed: {}, retrying in {:?}", what, e, backoff);
+                }
+                Err(e) => return Err(e),
+            }
+            tokio::time::delay_for(backoff).await;
+            backoff *= 2;
+            attempt += 1;
+        }
+    }
+    /// The multipart body of a request with files. It is built again for each
+    /// attempt since a sent form can't be reused.
+    fn form(req: &Request) -> reqwest::multipart::Form {
+        let mut form = reqwest::multipart::Form::new();
+        if let Some(body) = &req.body {
+            form = form.text("payload_json", body.to_string());
+        }
+        for (i, (filename, data)) in req.files.iter().enumerate() {
+            let part = reqwest::multipart::Part::bytes(data.clone()).file_name(filename.clone());
+            form = form.part(format!("file{}", i), part);
+        }
+        form
+    }
+    /// Sends `req` and decodes the JSON response as `T`.
+    async fn request<T: DeserializeOwned>(&self, req: Request) -> DiscordResult<T> {
+        let body = self.send_request(req).await?;
+        Ok(serde_json::from_str::<T>(&body)?)
+    }
+    /// Reads a REST response body, turning error statuses into the matching
+    /// `DiscordError`.
+    fn check_response(what: &str, res: Response) -> DiscordResult<String> {
+        let Response {
+            status,
+            headers,
+            body,
+        } = res;
+        println!("{} -> {} {}", what, status, body);
+        if status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            // not every 429 comes from the API, e.g. a Cloudflare ban page
+            let global = match serde_json::from_str::<RateLimitResponse>(&body) {
+                Ok(limit) => limit.global,
+                Err(_) => headers.contains_key("x-ratelimit-global"),
+            };
+            return Err(DiscordError::RateLimited {
+                retry_after: retry_after(&headers, &body),
+                global,
+            });
+        }
+        if !status.is_success() {
+            return Err(match serde_json::from_str::<ErrorResponse>(&body) {
+                Ok(e) => DiscordError::Api {
+                    status,
+                    code: e.code,
+                    message: e.message,
+                },
+                Err(_) => DiscordError::Status { status, body },
+            });
+        }
+        Ok(body)
+    }
+    pub async fn create_channel(&self, id: &str) -> DiscordResult<Channel> {
+        self.request(
+            Request::post("/users/@me/channels".to_string()).json(json!({ "recipient_id": id })),
+        )
+        .await
+    }
+    /// Sends a message, either plain text or built with `CreateMessage`.
+    /// Messages with files are uploaded as multipart/form-data.
+    pub async fn create_msg(
+        &self,
+        chan_id: &str,
+        msg: impl Into<CreateMessage>,
+    ) -> DiscordResult<Message> {
+        let msg = msg.into();
+        // embeds built by hand haven't been through the builder's checks
+        if let Some(embed) = &msg.embed {
+            embed.validate()?;
+        }
+        let req = Request::post(format!("/channels/{}/messages", chan_id))
+            .json(msg.payload())
+            .files(msg.files);
+        self.request(req).await
+    }
+
+    pub async fn edit_msg(
+        &self,
+        chan_id: &str,
+        msg_id: &str,
+        edit: EditMessage,
+    ) -> DiscordResult<Message> {
+        if let Some(Some(embed)) = &edit.embed {
+            embed.validate()?;
+        }
+        let req = Request::patch(format!("/channels/{}/messages/{}", chan_id, msg_id))
+            .json(edit.payload());
+        self.request(req).await
+    }
+    pub async fn delete_msg(&self, chan_id: &str, msg_id: &str) -> DiscordResult<()> {
+        let req = Request::delete(format!("/channels/{}/messages/{}", chan_id, msg_id));
+        self.send_request(req).await?;
+        Ok(())
+    }
+    /// Deletes messages 100 at a time, the most bulk delete takes. Messages
+    /// older than two weeks can't be bulk deleted and are skipped, and a lone
+    /// message is deleted on its own. `reason` goes in the audit log, e.g.
+    /// "Spam cleanup". Returns how many were deleted.
+    pub async fn bulk_delete_msgs(
+        &self,
+        chan_id: &str,
+        msg_ids: &[&str],
+        reason: Option<&str>,
+    ) -> DiscordResult<usize> {
+        let recent: Vec<_> = msg_ids
+            .iter()
+            .filter(|id| snowflake_age(id).is_some_and(|age| age < BULK_DELETE_MAX_AGE))
+            .collect();
+        if recent.len() < msg_ids.len() {
+            println!(
+                "Skipping {} message(s) too old to bulk delete",
+                msg_ids.len() - recent.len()
+            );
+        }
+        for batch in recent.chunks(100) {
+            let mut req = match batch {
+                [id] => Request::delete(format!("/channels/{}/messages/{}", chan_id, id)),
+                _ => Request::post(format!("/channels/{}/messages/bulk-delete", chan_id))
+                    .json(json!({ "messages": batch })),
+            };
+            if let Some(reason) = reason {
+                req = req.reason(reason);
+            }
+            self.send_request(req).await?;
+        }
+        Ok(recent.len())
+    }
+
+    /// Reads a channel's history as a stream of messages, fetching a page
+    /// of 100 whenever the previous one runs out. The stream ends after the
+    /// last message or the first error.
+    pub fn history(
+        &self,
+        chan_id: &str,
+        from: History,
+    ) -> BoxStream<'static, DiscordResult<Message>> {
+        let cursor = HistoryCursor {
+            http: self.clone(),
+            chan_id: chan_id.to_string(),
+            next: Some(from),
+            page: std::collections::VecDeque::new(),
+        };
+        Box::pin(futures_util::stream::unfold(
+            Some(cursor),
+            |cursor| async move {
+                let mut cursor = cursor?;
+                if cursor.page.is_empty() {
+                    if let Err(e) = cursor.next_page().await {
+                        return Some((Err(e), None));
+                    }
+                }
+                let msg = cursor.page.pop_front()?;
+                Some((Ok(msg), Some(cursor)))
+            },
+        ))
+    }
+
+    pub async fn get_gateway_bot(&self) -> DiscordResult<GatewayBot> {
+        self.request(Request::get("/gateway/bot".to_string())).await
+    }
+    pub async fn get_channel_message(&self, chan: &str, msg: &str) -> DiscordResult<Message> {
+        self.request(Request::get(format!("/channels/{}/messages/{}", chan, msg)))
+            .await
+    }
+    pub async fn create_reaction(&self, chan: &str, msg: &str, emoji: &str) -> DiscordResult<()> {
+        let req = Request::put(format!(
+            "/channels/{}/messages/{}/reactions/{}/@me",
+            chan, msg, emoji
+        ));
+        self.send_request(req).await?;
+        Ok(())
+    }
+}
+
+/// A REST response with its body read.
+struct Response {
+    status: reqwest::StatusCode,
+    headers: reqwest::header::HeaderMap,
+    body: String,
+}
+
+/// Stand-in answer to a request made while offline: the message a send, edit
+/// or fetch would have returned, an empty page of history, a DM channel, or
+/// `{}` for everything else.
+fn offline_response(req: &Request) -> serde_json::Value {
+    let path = req.path.split('?').next().unwrap_or_default();
+    let segments: Vec<_> = path.split('/').collect();
+    let message = match (req.method.as_str(), segments.as_slice()) {
+        ("POST", ["", "channels", channel_id, "messages"]) => (*channel_id, "0"),
+        ("PATCH", ["", "channels", channel_id, "messages", id])
+        | ("GET", ["", "channels", channel_id, "messages", id]) => (*channel_id, *id),
+        ("GET", ["", "channels", _, "messages"]) => return json!([]),
+        ("POST", ["", "users", "@me", "channels"]) => return json!({ "id": "0", "type": 1 }),
+        _ => return json!({}),
+    };
+    let sent = req.body.clone().unwrap_or_default();
+    json!({
+        "id": message.1,
+        "channel_id": message.0,
+        "author": { "id": "0", "username": "offline", "bot": true },
+        "content": sent["content"].as_str().unwrap_or(""),
+        "timestamp": "1970-01-01T00:00:00+00:00",
+        "edited_timestamp": null,
+        "tts": sent["tts"] == true,
+        "embeds": sent.get("embed").filter(|e| e.is_object()).into_iter().collect::<Vec<_>>()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::discordmock::*;
+
+    /// A snowflake created `age` ago.
+    fn snowflake(age: std::time::Duration) -> String {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap();
+        (((now - age).as_millis() as u64 - DISCORD_EPOCH) << 22).to_string()
+    }
+
+    #[tokio::test]
+    async fn sends_replies_and_returns_the_created_message() {
+        let rest = MockRest::start().await;
+        let http = mock_http(&rest);
+        let msg = CreateMessage::new()
+            .content("<@300> has 2 karma")
+            .reply_to("1001")
+            .allowed_mentions(AllowedMentions::none().user("300"))
+            .tts(true)
+            .nonce("n1");
+        let created = http.create_msg("200", msg).await.unwrap();
+        assert_eq!(created.channel_id, "200");
+        assert_eq!(created.content, "<@300> has 2 karma");
+        assert!(created.tts);
+
+        let requests = rest.requests();
+        assert_eq!(requests[0].path, "/api/v6/channels/200/messages");
+        assert_eq!(
+            requests[0].json(),
+            json!({
+                "content": "<@300> has 2 karma",
+                "message_reference": { "message_id": "1001" },
+                "allowed_mentions": {
+                    "parse": [],
+                    "users": ["300"],
+                    "roles": [],
+                    "replied_user": false
+                },
+                "tts": true,
+                "nonce": "n1"
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn embeds_over_the_limits_are_not_sent() {
+        let rest = MockRest::start().await;
+        let http = mock_http(&rest);
+        let embed = Embed {
+            title: Some("x".repeat(257)),
+            ..Embed::default()
+        };
+        let too_long = |e| matches!(e, DiscordError::EmbedLimit { what: "title", .. });
+        let msg = CreateMessage::new().embed(embed.clone());
+        assert!(http
+            .create_msg("200", msg)
+            .await
+            .err()
+            .is_some_and(too_long));
+        let edit = EditMessage::new().embed(embed);
+        assert!(http
+            .edit_msg("200", "1", edit)
+            .await
+            .err()
+            .is_some_and(too_long));
+        assert!(rest.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn offline_calls_answer_with_stand_ins() {
+        let rest = MockRest::start().await;
+        let mut http = mock_http(&rest);
+        http.set_offline(true);
+        let sent = http.create_msg("200", "hi").await.unwrap();
+        assert_eq!(
+            (sent.channel_id.as_str(), sent.content.as_str()),
+            ("200", "hi")
+        );
+        let edited = http
+            .edit_msg("200", "1001", EditMessage::new().content("edited"))
+            .await
+            .unwrap();
+        assert_eq!(
+            (edited.id.as_str(), edited.content.as_str()),
+            ("1001", "edited")
+        );
+        assert_eq!(
+            http.get_channel_message("200", "1001").await.unwrap().id,
+            "1001"
+        );
+        let mut history = http.history("200", History::Latest);
+        assert!(futures_util::StreamExt::next(&mut history).await.is_none());
+        assert!(rest.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn bulk_delete_skips_old_messages() {
+        let rest = MockRest::start().await;
+        let http = mock_http(&rest);
+        let day = std::time::Duration::from_secs(24 * 60 * 60);
+        let (new, newer, old) = (snowflake(day), snowflake(day / 2), snowflake(day * 15));
+
+        let deleted = http
+            .bulk_delete_msgs("200", &[&new, &old, &newer], Some("Spam cleanup"))
+            .await
+            .unwrap();
+        assert_eq!(deleted, 2);
+        let deleted = http
+            .bulk_delete_msgs("200", &[&old, &new], None)
+            .await
+            .unwrap();
+        assert_eq!(deleted, 1);
+
+        let requests = rest.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(
+            (requests[0].method.as_str(), requests[0].path.as_str()),
+            ("POST", "/api/v6/channels/200/messages/bulk-delete")
+        );
+        assert_eq!(requests[0].json(), json!({ "messages": [new, newer] }));
+        assert_eq!(requests[0].headers["x-audit-log-reason"], "Spam cleanup");
+        assert_eq!(requests[1].method, "DELETE");
+        assert!(!requests[1].headers.contains_key("x-audit-log-reason"));
+        assert_eq!(
+            requests[1].path,
+            format!("/api/v6/channels/200/messages/{}", new)
+        );
+    }
+
+    #[tokio::test]
+    async fn edits_replace_content_and_remove_embeds() {
+        let rest = MockRest::start().await;
+        let http = mock_http(&rest);
+        let edit = EditMessage::new().content("leaderboard").remove_embed();
+        http.edit_msg("200", "1001", edit).await.unwrap();
+        let requests = rest.requests();
+        assert_eq!(requests[0].method, "PATCH");
+        assert_eq!(requests[0].path, "/api/v6/channels/200/messages/1001");
+        assert_eq!(
+            requests[0].json(),
+            json!({ "content": "leaderboard", "embed": null })
+        );
+    }
+
+    fn messages(ids: impl Iterator<Item = u64>) -> serde_json::Value {
+        let messages: Vec<_> = ids
+            .map(|id| {
+                json!({
+                    "id": id.to_string(),
+                    "channel_id": "200",
+                    "author": { "id": "300" },
+                    "content": "",
+                    "timestamp": "2020-01-01T00:00:00+00:00",
+                    "edited_timestamp": null,
+                    "tts": false
+                })
+            })
+            .collect();
+        json!(messages)
+    }
+
+    #[tokio::test]
+    async fn history_pages_lazily_until_it_runs_out() {
+        use futures_util::StreamExt;
+
+        let rest = MockRest::start().await;
+        let http = mock_http(&rest);
+        let path = "/api/v6/channels/200/messages?limit=100";
+        // the first page is read twice
+        rest.respond(
+            "GET",
+            &format!("{}&before=1000", path),
+            200,
+            messages((900..1000).rev()),
+        );
+        rest.respond(
+            "GET",
+            &format!("{}&before=1000", path),
+            200,
+            messages((900..1000).rev()),
+        );
+        rest.respond(
+            "GET",
+            &format!("{}&before=900", path),
+            200,
+            messages((870..900).rev()),
+        );
+        // pages going forward may come newest first too
+        rest.respond(
+            "GET",
+            &format!("{}&after=0", path),
+            200,
+            messages((1..101).rev()),
+        );
+        rest.respond(
+            "GET",
+            &format!("{}&after=100", path),
+            200,
+            messages(101..103),
+        );
+
+        let first: Vec<_> = http
+            .history("200", History::Before("1000".to_string()))
+            .take(3)
+            .collect()
+            .await;
+        let ids: Vec<_> = first.into_iter().map(|m| m.unwrap().id).collect();
+        assert_eq!(ids, ["999", "998", "997"]);
+        assert_eq!(rest.requests().len(), 1);
+
+        let all: Vec<_> = http
+            .history("200", History::Before("1000".to_string()))
+            .collect()
+            .await;
+        assert_eq!(all.len(), 130);
+        assert_eq!(all[129].as_ref().unwrap().id, "870");
+
+        let forward: Vec<_> = http
+            .history("200", History::After("0".to_string()))
+            .map(|m| m.unwrap().id.parse::<u64>().unwrap())
+            .collect()
+            .await;
+        assert_eq!(forward, (1..103).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn uploads_files_with_the_message_as_multipart() {
+        let rest = MockRest::start().await;
+        let http = mock_http(&rest);
+        let msg = CreateMessage::new()
+            .content("karma export")
+            .file("karma.csv", b"name,karma\nrust,2\n".to_vec())
+            .file("notes.txt", b"none".to_vec());
+        http.create_msg("200", msg).await.unwrap();
+
+        let request = &rest.requests()[0];
+        assert!(request.headers["content-type"].starts_with("multipart/form-data; boundary="));
+        let body = &request.body;
+        assert!(body.contains("name=\"payload_json\""), "{}", body);
+        assert!(body.contains(r#"{"content":"karma export"}"#), "{}", body);
+        assert!(
+            body.contains("name=\"file0\"; filename=\"karma.csv\""),
+            "{}",
+            body
+        );
+        assert!(body.contains("name,karma\nrust,2\n"), "{}", body);
+        assert!(
+            body.contains("name=\"file1\"; filename=\"notes.txt\""),
+            "{}",
+            body
+        );
+    }
+
+    #[tokio::test]
+    async fn rate_limited_uploads_are_sent_again() {
+        let rest = MockRest::start().await;
+        let http = mock_http(&rest);
+        let body = json!({ "message": "You are being rate limited.", "retry_after": 100, "global": false });
+        rest.respond_with_headers(
+            "POST",
+            "/api/v6/channels/200/messages",
+            429,
+            &[("retry-after", "100"), ("x-ratelimit-reset-after", "0.1")],
+            body,
+        );
+        let msg = CreateMessage::new().file("karma.csv", b"name,karma\n".to_vec());
+        http.create_msg("200", msg).await.unwrap();
+
+        let requests = rest.requests();
+        assert_eq!(requests.len(), 2);
+        for request in &requests {
+            assert!(
+                request.body.contains("filename=\"karma.csv\""),
+                "{}",
+                request.body
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn server_errors_are_retried() {
+        let rest = MockRest::start().await;
+        let http = mock_http(&rest);
+        let path = "/api/v6/channels/200/messages/1";
+        rest.respond("DELETE", path, 502, json!({ "message": "Bad Gateway" }));
+        http.delete_msg("200", "1").await.unwrap();
+        assert_eq!(rest.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn client_errors_are_returned_without_retrying() {
+        let rest = MockRest::start().await;
+        let http = mock_http(&rest);
+        let path = "/api/v6/channels/200/messages/1";
+        let body = json!({ "code": 50013, "message": "Missing Permissions" });
+        rest.respond("DELETE", path, 403, body);
+        match http.delete_msg("200", "1").await {
+            Err(DiscordError::Api { code, .. }) => assert_eq!(code, 50013),
+            other => panic!("expected an API error, got {:?}", other),
+        }
+        assert_eq!(rest.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn rate_limits_without_an_api_body_are_still_rate_limits() {
+        let rest = MockRest::start().await;
+        let http = mock_http(&rest);
+        let path = "/api/v6/channels/200/messages/1";
+        let headers = [
+            ("x-ratelimit-reset-after", "0.05"),
+            ("x-ratelimit-global", "true"),
+        ];
+        for _ in 0..REST_ATTEMPTS {
+            let page = json!("<html><body>You are being rate limited</body></html>");
+            rest.respond_with_headers("DELETE", path, 429, &headers, page);
+        }
+        match http.delete_msg("200", "1").await {
+            Err(DiscordError::RateLimited {
+                retry_after,
+                global,
+            }) => {
+                assert_eq!(retry_after, std::time::Duration::from_millis(50));
+                assert!(global);
+            }
+            other => panic!("expected a rate limit, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn audit_log_reasons_are_percent_encoded() {
+        let rest = MockRest::start().await;
+        let http = mock_http(&rest);
+        let req = Request::delete("/channels/200/messages/1".to_string()).reason("spam: 🔥 x2");
+        http.send_request(req).await.unwrap();
+        assert_eq!(
+            rest.requests()[0].headers["x-audit-log-reason"],
+            "spam%3A %F0%9F%94%A5 x2"
+        );
+    }
+}
@@ -1,4 +1,5 @@
-use serde::Deserialize;
+use crate::discorderror::*;
+use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Emoji {
@@ -13,18 +14,19 @@ pub struct Reaction {
     pub emoji: Emoji,
 }
 
+/// Body of a 429 response.
 #[derive(Debug, Deserialize)]
-#[serde(untagged)]
-pub enum RateLimited<T> {
-    RateLimit {
-        global: bool,
-        message: String,
-        retry_after: u32,
-    },
-    Success {
-        #[serde(flatten)]
-        data: T,
-    },
+pub struct RateLimitResponse {
+    pub global: bool,
+    pub message: String,
+    pub retry_after: u32,
+}
+
+/// Body of an API error response, e.g. `{"code": 50001, "message": "Missing Access"}`.
+#[derive(Debug, Deserialize)]
+pub struct ErrorResponse {
+    pub code: u32,
+    pub message: String,
 }
 
 #[derive(Debug, Deserialize)]
@@ -39,19 +41,178 @@ pub struct Message {
     pub edited_timestamp: Option<String>,
     pub tts: bool,
     pub reactions: Option<Vec<Reaction>>,
+    #[serde(default)]
+    pub embeds: Vec<Embed>,
+    #[serde(default)]
+    pub attachments: Vec<Attachment>,
 }
 
-#[derive(Debug, Deserialize)]
-#[serde(untagged)]
-pub enum CreateMessageResponse {
-    RateLimit {
-        global: bool,
-        message: String,
-        retry_after: u32,
-    },
-    Success {
-        id: String,
-    },
+/// A file attached to a message.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Attachment {
+    pub id: String,
+    pub filename: String,
+    pub size: u64,
+    pub url: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct EmbedField {
+    pub name: String,
+    pub value: String,
+    #[serde(default)]
+    pub inline: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct EmbedFooter {
+    pub text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub icon_url: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct EmbedAuthor {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub icon_url: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct EmbedThumbnail {
+    pub url: String,
+}
+
+/// Rich content of a message. Build embeds to send with `Embed::builder()`,
+/// which checks them against Discord's limits.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct Embed {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// ISO8601 timestamp shown in the footer.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub color: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub footer: Option<EmbedFooter>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thumbnail: Option<EmbedThumbnail>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub author: Option<EmbedAuthor>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<EmbedField>,
+}
+
+impl Embed {
+    pub fn builder() -> EmbedBuilder {
+        EmbedBuilder::default()
+    }
+    /// Checks the embed against Discord's limits, which the API would
+    /// otherwise answer with a 400.
+    pub fn validate(&self) -> DiscordResult<()> {
+        fn check(what: &'static str, s: Option<&String>, max: usize) -> DiscordResult<usize> {
+            let len = s.map_or(0, |s| s.chars().count());
+            if len > max {
+                return Err(DiscordError::EmbedLimit { what, len, max });
+            }
+            Ok(len)
+        }
+        let mut total = check("title", self.title.as_ref(), 256)?
+            + check("description", self.description.as_ref(), 4096)?
+            + check("footer text", self.footer.as_ref().map(|f| &f.text), 2048)?
+            + check("author name", self.author.as_ref().map(|a| &a.name), 256)?;
+        if self.fields.len() > 25 {
+            return Err(DiscordError::EmbedLimit {
+                what: "fields",
+                len: self.fields.len(),
+                max: 25,
+            });
+        }
+        for field in &self.fields {
+            total += check("field name", Some(&field.name), 256)?
+                + check("field value", Some(&field.value), 1024)?;
+        }
+        if total > 6000 {
+            return Err(DiscordError::EmbedLimit {
+                what: "text",
+                len: total,
+                max: 6000,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Builds an `Embed`, e.g.
+/// `Embed::builder().title("Karma").field("rust", "2", true).build()?`.
+#[derive(Debug, Clone, Default)]
+pub struct EmbedBuilder {
+    embed: Embed,
+}
+
+impl EmbedBuilder {
+    pub fn title(mut self, title: &str) -> EmbedBuilder {
+        self.embed.title = Some(title.to_string());
+        self
+    }
+    pub fn description(mut self, description: &str) -> EmbedBuilder {
+        self.embed.description = Some(description.to_string());
+        self
+    }
+    /// Makes the title a link.
+    pub fn url(mut self, url: &str) -> EmbedBuilder {
+        self.embed.url = Some(url.to_string());
+        self
+    }
+    pub fn timestamp(mut self, timestamp: &str) -> EmbedBuilder {
+        self.embed.timestamp = Some(timestamp.to_string());
+        self
+    }
+    /// Color of the stripe along the embed, as 0xRRGGBB.
+    pub fn color(mut self, color: u32) -> EmbedBuilder {
+        self.embed.color = Some(color);
+        self
+    }
+    pub fn footer(mut self, text: &str, icon_url: Option<&str>) -> EmbedBuilder {
+        self.embed.footer = Some(EmbedFooter {
+            text: text.to_string(),
+            icon_url: icon_url.map(str::to_string),
+        });
+        self
+    }
+    pub fn author(mut self, name: &str, url: Option<&str>, icon_url: Option<&str>) -> EmbedBuilder {
+        self.embed.author = Some(EmbedAuthor {
+            name: name.to_string(),
+            url: url.map(str::to_string),
+            icon_url: icon_url.map(str::to_string),
+        });
+        self
+    }
+    pub fn thumbnail(mut self, url: &str) -> EmbedBuilder {
+        self.embed.thumbnail = Some(EmbedThumbnail {
+            url: url.to_string(),
+        });
+        self
+    }
+    pub fn field(mut self, name: &str, value: &str, inline: bool) -> EmbedBuilder {
+        self.embed.fields.push(EmbedField {
+            name: name.to_string(),
+            value: value.to_string(),
+            inline,
+        });
+        self
+    }
+    pub fn build(self) -> DiscordResult<Embed> {
+        self.embed.validate()?;
+        Ok(self.embed)
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -70,6 +231,7 @@ pub struct ReadyMessage {
     pub user: User,
     pub session_id: String,
     pub guilds: Vec<UnavailableGuild>,
+    pub shard: Option<(u64, u64)>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -83,6 +245,21 @@ pub struct HelloMessage {
     pub heartbeat_interval: u64,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct SessionStartLimit {
+    pub total: u32,
+    pub remaining: u32,
+    pub reset_after: u64,
+    pub max_concurrency: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GatewayBot {
+    pub url: String,
+    pub shards: u64,
+    pub session_start_limit: SessionStartLimit,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct UnavailableGuild {
     pub id: String,
@@ -114,10 +291,27 @@ pub struct GuildMember {
     pub roles: Vec<String>,
 }
 
+/// ETF carries integers wider than 32 bits as big integers, which the decoder
+/// hands over as decimal strings so snowflakes match their JSON form. The few
+/// non-snowflake fields that large accept either representation.
+fn u64_or_string<'de, D: serde::Deserializer<'de>>(d: D) -> Result<u64, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Repr {
+        Int(u64),
+        Str(String),
+    }
+    match Repr::deserialize(d)? {
+        Repr::Int(v) => Ok(v),
+        Repr::Str(v) => v.parse().map_err(serde::de::Error::custom),
+    }
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct Activity {
     pub name: String,
     pub r#type: u32,
+    #[serde(deserialize_with = "u64_or_string")]
     pub created_at: u64,
     pub application_id: Option<String>,
     pub details: Option<String>,
@@ -136,11 +330,28 @@ pub struct ClientStatus {
 pub struct PresenceUpdate {
     pub user: User,
     pub game: Option<Activity>,
+    /// Left out of the presences in GUILD_MEMBERS_CHUNK.
+    #[serde(default)]
     pub guild_id: String,
     pub client_status: ClientStatus,
     pub nick: Option<String>,
 }
 
+/// One of the GUILD_MEMBERS_CHUNK events answering a Request Guild Members.
+#[derive(Debug, Deserialize, Clone)]
+pub struct GuildMembersChunk {
+    pub guild_id: String,
+    pub members: Vec<GuildMember>,
+    pub chunk_index: u32,
+    pub chunk_count: u32,
+    /// Requested user ids that are not members of the guild.
+    #[serde(default)]
+    pub not_found: Vec<String>,
+    #[serde(default)]
+    pub presences: Vec<PresenceUpdate>,
+    pub nonce: Option<String>,
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct TypingStart {
     pub user_id: String,
@@ -170,11 +381,16 @@ pub enum DiscordMessage {
         s: u64,
         d: Message,
     },
+    GuildMembersChunk {
+        s: u64,
+        d: GuildMembersChunk,
+    },
     Unknown {
         s: u64,
         t: String,
         d: serde_json::Value,
     },
+    Heartbeat {},
     Reconnect {},
     InvalidSession {},
     HeartbeatAck {},
@@ -191,7 +407,9 @@ impl DiscordMessage {
             Self::GuildCreate { s, .. } => Some(*s),
             Self::PresenceUpdate { s, .. } => Some(*s),
             Self::MessageCreate { s, .. } => Some(*s),
+            Self::GuildMembersChunk { s, .. } => Some(*s),
             Self::Unknown { s, .. } => Some(*s),
+            Self::Heartbeat {} => None,
             Self::Reconnect {} => None,
             Self::InvalidSession {} => None,
             Self::HeartbeatAck {} => None,
@@ -200,6 +418,80 @@ impl DiscordMessage {
     }
 }
 
+/// Source of the "d" field of a gateway payload. JSON payloads put it after
+/// the discriminators so it can be read straight from the map, but ETF maps are
+/// ordered by key, so there it has to be buffered first.
+trait Payload<'de> {
+    type Error: serde::de::Error;
+    fn get<T: serde::Deserialize<'de>>(self) -> Result<T, Self::Error>;
+}
+
+struct Streamed<'a, V>(&'a mut V);
+
+impl<'de, 'a, V: serde::de::MapAccess<'de>> Payload<'de> for Streamed<'a, V> {
+    type Error = V::Error;
+    fn get<T: serde::Deserialize<'de>>(self) -> Result<T, V::Error> {
+        self.0.next_value()
+    }
+}
+
+struct Buffered<E>(serde_json::Value, std::marker::PhantomData<E>);
+
+impl<'de, E: serde::de::Error> Payload<'de> for Buffered<E> {
+    type Error = E;
+    fn get<T: serde::Deserialize<'de>>(self) -> Result<T, E> {
+        T::deserialize(self.0).map_err(E::custom)
+    }
+}
+
+fn dispatch<'de, P: Payload<'de>>(
+    op: u64,
+    t: Option<String>,
+    s: Option<u64>,
+    d: P,
+) -> Result<DiscordMessage, P::Error> {
+    use serde::de;
+
+    match (op, t) {
+        (0, Some(t)) => {
+            let s = s.ok_or_else(|| de::Error::custom("dispatch without sequence number"))?;
+            if t == "READY" {
+                Ok(DiscordMessage::Ready { s, d: d.get()? })
+            } else if t == "RESUMED" {
+                Ok(DiscordMessage::Resumed { s, d: d.get()? })
+            } else if t == "GUILD_CREATE" {
+                Ok(DiscordMessage::GuildCreate { s, d: d.get()? })
+            } else if t == "PRESENCE_UPDATE" {
+                Ok(DiscordMessage::PresenceUpdate { s, d: d.get()? })
+            } else if t == "MESSAGE_CREATE" {
+                Ok(DiscordMessage::MessageCreate { s, d: d.get()? })
+            } else if t == "GUILD_MEMBERS_CHUNK" {
+                Ok(DiscordMessage::GuildMembersChunk { s, d: d.get()? })
+            } else {
+                Ok(DiscordMessage::Unknown { t, s, d: d.get()? })
+            }
+        }
+        (1, _) => {
+            d.get::<de::IgnoredAny>()?;
+            Ok(DiscordMessage::Heartbeat {})
+        }
+        (7, _) => {
+            d.get::<de::IgnoredAny>()?;
+            Ok(DiscordMessage::Reconnect {})
+        }
+        (9, _) => {
+            d.get::<de::IgnoredAny>()?;
+            Ok(DiscordMessage::InvalidSession {})
+        }
+        (10, _) => Ok(DiscordMessage::Hello { d: d.get()? }),
+        (11, _) => {
+            d.get::<de::IgnoredAny>()?;
+            Ok(DiscordMessage::HeartbeatAck {})
+        }
+        (op, t) => Err(de::Error::unknown_variant(&format!("{},{:?}", op, t), &[])),
+    }
+}
+
 impl<'de> serde::Deserialize<'de> for DiscordMessage {
     fn deserialize<D: serde::Deserializer<'de>>(d: D) -> Result<DiscordMessage, D::Error> {
         struct MessageVisitor;
@@ -220,6 +512,7 @@ impl<'de> serde::Deserialize<'de> for DiscordMessage {
                 let mut op = None;
                 let mut t: Option<Option<String>> = None;
                 let mut s: Option<Option<u64>> = None;
+                let mut d: Option<serde_json::Value> = None;
                 while let Some(key) = map.next_key::<&'de str>()? {
                     match key {
                         "op" => {
@@ -241,83 +534,82 @@ impl<'de> serde::Deserialize<'de> for DiscordMessage {
                             s = Some(map.next_value()?);
                         }
                         "d" => {
-                            if !op.is_some() || !s.is_some() || !t.is_some() {
-                                return Err(de::Error::custom(
-                                    "payload field must come after discriminators",
-                                ));
+                            if d.is_some() {
+                                return Err(de::Error::duplicate_field("d"));
                             }
-                            return match (op.unwrap(), t.unwrap()) {
-                                (0, Some(t)) => {
-                                    if t == "READY" {
-                                        Ok(DiscordMessage::Ready {
-                                            s: s.unwrap().unwrap(),
-                                            d: map.next_value()?,
-                                        })
-                                    } else if t == "RESUMED" {
-                                        Ok(DiscordMessage::Resumed {
-                                            s: s.unwrap().unwrap(),
-                                            d: map.next_value()?,
-                                        })
-                                    } else if t == "GUILD_CREATE" {
-                                        Ok(DiscordMessage::GuildCreate {
-                                            s: s.unwrap().unwrap(),
-                                            d: map.next_value()?,
-                                        })
-                                    } else if t == "PRESENCE_UPDATE" {
-                                        Ok(DiscordMessage::PresenceUpdate {
-                                            s: s.unwrap().unwrap(),
-                                            d: map.next_value()?,
-                                        })
-                                    } else if t == "MESSAGE_CREATE" {
-                                        Ok(DiscordMessage::MessageCreate {
-                                            s: s.unwrap().unwrap(),
-                                            d: map.next_value()?,
-                                        })
-                                    } else {
-                                        Ok(DiscordMessage::Unknown {
-                                            t,
-                                            s: s.unwrap().unwrap(),
-                                            d: map.next_value()?,
-                                        })
-                                    }
-                                }
-                                (7, _) => {
-                                    map.next_value::<de::IgnoredAny>()?;
-                                    Ok(DiscordMessage::Reconnect {})
-                                }
-                                (9, _) => {
-                                    map.next_value::<de::IgnoredAny>()?;
-                                    Ok(DiscordMessage::InvalidSession {})
-                                }
-                                (10, _) => Ok(DiscordMessage::Hello {
-                                    d: map.next_value()?,
-                                }),
-                                (11, _) => {
-                                    map.next_value::<de::IgnoredAny>()?;
-                                    Ok(DiscordMessage::HeartbeatAck {})
-                                }
-                                (op, t) => {
-                                    Err(de::Error::unknown_variant(&format!("{},{:?}", op, t), &[]))
-                                }
-                            };
+                            if let (Some(op), Some(t), Some(s)) = (op, &t, s) {
+                                return dispatch(op, t.clone(), s, Streamed(&mut map));
+                            }
+                            d = Some(map.next_value()?);
                         }
                         k => {
                             return Err(de::Error::unknown_field(k, &["op", "t", "s", "d"]));
                         }
                     }
                 }
-                if !op.is_some() {
-                    return Err(de::Error::missing_field("op"));
-                }
-                if !s.is_some() {
-                    return Err(de::Error::missing_field("s"));
-                }
-                if !t.is_some() {
-                    return Err(de::Error::missing_field("t"));
+                let op = op.ok_or_else(|| de::Error::missing_field("op"))?;
+                let s = s.ok_or_else(|| de::Error::missing_field("s"))?;
+                let t = t.ok_or_else(|| de::Error::missing_field("t"))?;
+                match d {
+                    Some(d) => dispatch(op, t, s, Buffered(d, std::marker::PhantomData)),
+                    None => Err(de::Error::missing_field("d")),
                 }
-                return Err(de::Error::missing_field("d"));
             }
         }
         d.deserialize_map(MessageVisitor)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn embeds_over_discord_limits_are_refused() {
+        let embed = Embed::builder()
+            .title("Karma")
+            .field("rust", "2", true)
+            .footer("whois", None)
+            .build()
+            .unwrap();
+        assert_eq!(
+            serde_json::to_value(&embed).unwrap(),
+            serde_json::json!({
+                "title": "Karma",
+                "footer": { "text": "whois" },
+                "fields": [{ "name": "rust", "value": "2", "inline": true }]
+            })
+        );
+
+        let long = "\u{e9}".repeat(257);
+        assert!(matches!(
+            Embed::builder().title(&long).build(),
+            Err(DiscordError::EmbedLimit {
+                what: "title",
+                len: 257,
+                max: 256
+            })
+        ));
+        let fields = (0..26).fold(Embed::builder(), |b, i| b.field(&i.to_string(), "x", false));
+        assert!(matches!(
+            fields.build(),
+            Err(DiscordError::EmbedLimit {
+                what: "fields",
+                len: 26,
+                ..
+            })
+        ));
+        let value = "x".repeat(1000);
+        let text = (0..7).fold(Embed::builder(), |b, i| {
+            b.field(&i.to_string(), &value, false)
+        });
+        assert!(matches!(
+            text.build(),
+            Err(DiscordError::EmbedLimit {
+                what: "text",
+                len: 7007,
+                max: 6000
+            })
+        ));
+    }
+}
@@ -0,0 +1,377 @@
+//! In-process stand-ins for Discord's REST API and gateway, so clients and the
+//! agent can be exercised end to end without network access.
+use crate::discordclient::*;
+use crate::discordhttp::Http;
+use async_std::io::{ReadExt, WriteExt};
+use async_std::net::{TcpListener, TcpStream};
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tungstenite::Message;
+
+/// How long a test waits for the client before giving up.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A REST request as received by `MockRest`.
+#[derive(Debug, Clone)]
+pub struct RecordedRequest {
+    pub method: String,
+    pub path: String,
+    pub headers: HashMap<String, String>,
+    pub body: String,
+}
+
+impl RecordedRequest {
+    pub fn json(&self) -> Value {
+        serde_json::from_str(&self.body).unwrap_or(Value::Null)
+    }
+}
+
+/// Status, extra headers and body of a scripted REST response.
+type Response = (u16, Vec<(String, String)>, String);
+
+#[derive(Default)]
+struct RestState {
+    requests: Vec<RecordedRequest>,
+    /// Scripted responses by method and path, used up in order. Anything
+    /// unscripted gets `default_response`.
+    responses: HashMap<(String, String), VecDeque<Response>>,
+    /// Time taken to answer requests by method and path.
+    delays: HashMap<(String, String), Duration>,
+}
+
+/// A minimal HTTP/1.1 server recording every request and answering with
+/// scripted responses.
+pub struct MockRest {
+    pub url: String,
+    state: Arc<Mutex<RestState>>,
+}
+
+impl MockRest {
+    pub async fn start() -> MockRest {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/api", listener.local_addr().unwrap());
+        let state = Arc::new(Mutex::new(RestState::default()));
+        tokio::spawn(Self::serve(listener, state.clone()));
+        MockRest { url, state }
+    }
+    /// Queues a response for the next `method` request on `path`, e.g.
+    /// `respond("GET", "/api/v6/gateway/bot", 200, body)`.
+    pub fn respond(&self, method: &str, path: &str, status: u16, body: Value) {
+        self.respond_with_headers(method, path, status, &[], body);
+    }
+    pub fn respond_with_headers(
+        &self,
+        method: &str,
+        path: &str,
+        status: u16,
+        headers: &[(&str, &str)],
+        body: Value,
+    ) {
+        let headers = headers
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        self.state
+            .lock()
+            .unwrap()
+            .responses
+            .entry((method.to_string(), path.to_string()))
+            .or_default()
+            .push_back((status, headers, body.to_string()));
+    }
+    /// Answers every `method` request on `path` only after `delay`.
+    pub fn delay(&self, method: &str, path: &str, delay: Duration) {
+        self.state
+            .lock()
+            .unwrap()
+            .delays
+            .insert((method.to_string(), path.to_string()), delay);
+    }
+    /// Waits until at least `count` requests have been received.
+    pub async fn wait_for(&self, count: usize) {
+        let deadline = tokio::time::Instant::now() + TIMEOUT;
+        while self.requests().len() < count {
+            if tokio::time::Instant::now() > deadline {
+                panic!("timed out waiting for {} requests", count);
+            }
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+    }
+    /// Every request received so far, oldest first.
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.state.lock().unwrap().requests.clone()
+    }
+    async fn serve(listener: TcpListener, state: Arc<Mutex<RestState>>) {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(Self::handle(stream, state.clone()));
+        }
+    }
+    async fn handle(mut stream: TcpStream, state: Arc<Mutex<RestState>>) {
+        let mut buf = vec![];
+        let mut chunk = [0u8; 4096];
+        let (request, body_start, body_len) = loop {
+            let n = match stream.read(&mut chunk).await {
+                Ok(0) | Err(_) => return,
+                Ok(n) => n,
+            };
+            buf.extend_from_slice(&chunk[..n]);
+            let mut headers = [httparse::EMPTY_HEADER; 32];
+            let mut req = httparse::Request::new(&mut headers);
+            if let Ok(httparse::Status::Complete(len)) = req.parse(&buf) {
+                let headers: HashMap<_, _> = req
+                    .headers
+                    .iter()
+                    .map(|h| {
+                        (
+                            h.name.to_ascii_lowercase(),
+                            String::from_utf8_lossy(h.value).into_owned(),
+                        )
+                    })
+                    .collect();
+                let body_len = headers
+                    .get("content-length")
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(0);
+                let request = RecordedRequest {
+                    method: req.method.unwrap_or("").to_string(),
+                    path: req.path.unwrap_or("").to_string(),
+                    headers,
+                    body: String::new(),
+                };
+                break (request, len, body_len);
+            }
+        };
+        while buf.len() < body_start + body_len {
+            match stream.read(&mut chunk).await {
+                Ok(0) | Err(_) => return,
+                Ok(n) => buf.extend_from_slice(&chunk[..n]),
+            }
+        }
+        let mut request = request;
+        request.body =
+            String::from_utf8_lossy(&buf[body_start..body_start + body_len]).into_owned();
+
+        let ((status, headers, body), delay) = {
+            let mut state = state.lock().unwrap();
+            let key = (request.method.clone(), request.path.clone());
+            state.requests.push(request);
+            let delay = state.delays.get(&key).copied();
+            let response = state
+                .responses
+                .get_mut(&key)
+                .and_then(|queue| queue.pop_front())
+                .unwrap_or_else(|| default_response(state.requests.last().unwrap()));
+            (response, delay)
+        };
+        if let Some(delay) = delay {
+            tokio::time::delay_for(delay).await;
+        }
+        let mut response = format!(
+            "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n",
+            status,
+            body.len()
+        );
+        for (name, value) in headers {
+            response.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        response.push_str("\r\n");
+        response.push_str(&body);
+        let _ = stream.write_all(response.as_bytes()).await;
+    }
+}
+
+/// Answer to an unscripted request: the resulting message for a message sent
+/// to a channel or edited, `{}` otherwise.
+fn default_response(request: &RecordedRequest) -> Response {
+    let segments: Vec<_> = request.path.split('/').collect();
+    let message = match (request.method.as_str(), segments.as_slice()) {
+        ("POST", ["", "api", _, "channels", channel_id, "messages"]) => Some((*channel_id, "9001")),
+        ("PATCH", ["", "api", _, "channels", channel_id, "messages", id]) => {
+            Some((*channel_id, *id))
+        }
+        _ => None,
+    };
+    let body = match message {
+        Some((channel_id, id)) => {
+            let sent = request.json();
+            json!({
+                "id": id,
+                "channel_id": channel_id,
+                "author": { "id": "0", "username": "mock", "bot": true },
+                "content": sent["content"].as_str().unwrap_or(""),
+                "timestamp": "2020-01-01T00:00:00+00:00",
+                "edited_timestamp": null,
+                "tts": sent["tts"] == true
+            })
+        }
+        None => json!({}),
+    };
+    (200, vec![], body.to_string())
+}
+
+/// A gateway accepting one connection at a time. It greets every connection
+/// with Hello and acknowledges heartbeats; everything else is up to the test,
+/// which reads the client's payloads with `expect_op` and scripts the
+/// gateway's side with `send` and `dispatch`.
+pub struct MockGateway {
+    pub url: String,
+    script: mpsc::UnboundedSender<Message>,
+    received: mpsc::UnboundedReceiver<Value>,
+    seq: u64,
+}
+
+impl MockGateway {
+    pub async fn start() -> MockGateway {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let (script, script_rx) = mpsc::unbounded_channel();
+        let (received_tx, received) = mpsc::unbounded_channel();
+        tokio::spawn(Self::serve(listener, script_rx, received_tx));
+        MockGateway {
+            url,
+            script,
+            received,
+            seq: 0,
+        }
+    }
+    async fn serve(
+        listener: TcpListener,
+        mut script: mpsc::UnboundedReceiver<Message>,
+        received: mpsc::UnboundedSender<Value>,
+    ) {
+        while let Ok((stream, _)) = listener.accept().await {
+            let mut wss = match async_tungstenite::accept_async(stream).await {
+                Ok(wss) => wss,
+                Err(_) => continue,
+            };
+            let hello =
+                json!({ "op": 10, "s": null, "t": null, "d": { "heartbeat_interval": 45000 } });
+            if wss.send(Message::text(hello.to_string())).await.is_err() {
+                continue;
+            }
+            loop {
+                tokio::select! {
+                    msg = wss.next() => {
+                        let payload: Value = match msg {
+                            Some(Ok(Message::Text(text))) => match serde_json::from_str(&text) {
+                                Ok(payload) => payload,
+                                Err(_) => continue,
+                            },
+                            Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                            Some(Ok(_)) => continue,
+                        };
+                        if payload["op"] == 1 {
+                            let ack = json!({ "op": 11, "s": null, "t": null, "d": null });
+                            let _ = wss.send(Message::text(ack.to_string())).await;
+                        }
+                        let _ = received.send(payload);
+                    }
+                    msg = script.recv() => match msg {
+                        Some(msg) => {
+                            let close = msg.is_close();
+                            if wss.send(msg).await.is_err() || close {
+                                break;
+                            }
+                        }
+                        None => return,
+                    },
+                }
+            }
+        }
+    }
+    /// Sends a raw payload to the connected client. Like Discord's, payloads
+    /// other than dispatches should carry null `s` and `t`.
+    pub fn send(&self, payload: Value) {
+        self.script
+            .send(Message::text(payload.to_string()))
+            .unwrap();
+    }
+    /// Closes the connection with a close code, e.g. 4004 for a bad token.
+    pub fn close(&self, code: u16, reason: &str) {
+        let frame = tungstenite::protocol::CloseFrame {
+            code: code.into(),
+            reason: reason.to_string().into(),
+        };
+        self.script.send(Message::Close(Some(frame))).unwrap();
+    }
+    /// Sends an op 0 dispatch with the next sequence number.
+    pub fn dispatch(&mut self, t: &str, d: Value) {
+        self.seq += 1;
+        self.send(json!({ "op": 0, "t": t, "s": self.seq, "d": d }));
+    }
+    /// Readies the session of bot `user_id` in the given guilds.
+    pub fn ready(&mut self, user_id: &str, guilds: &[&str]) {
+        let guilds: Vec<_> = guilds.iter().map(|id| json!({ "id": id })).collect();
+        self.dispatch(
+            "READY",
+            json!({
+                "v": 6,
+                "user": { "id": user_id, "username": "bot" },
+                "session_id": "mock-session",
+                "guilds": guilds,
+                "shard": [0, 1]
+            }),
+        );
+    }
+    /// Delivers a guild message from user `author_id`. Its id is derived from
+    /// the sequence number of the dispatch.
+    pub fn message_create(&mut self, channel_id: &str, author_id: &str, content: &str) {
+        let d = json!({
+            "id": (1001 + self.seq).to_string(),
+            "channel_id": channel_id,
+            "author": { "id": author_id, "username": "user" },
+            "content": content,
+            "timestamp": "2020-01-01T00:00:00+00:00",
+            "edited_timestamp": null,
+            "tts": false
+        });
+        self.dispatch("MESSAGE_CREATE", d);
+    }
+    /// Waits for the client to send a payload with opcode `op`, skipping
+    /// others, and returns it.
+    pub async fn expect_op(&mut self, op: u64) -> Value {
+        loop {
+            let payload = tokio::time::timeout(TIMEOUT, self.received.recv())
+                .await
+                .unwrap_or_else(|_| panic!("timed out waiting for op {}", op))
+                .expect("gateway stopped");
+            if payload["op"] == op {
+                return payload;
+            }
+        }
+    }
+}
+
+/// Body of `GET /gateway/bot` for a single shard.
+pub fn gateway_bot(url: &str) -> Value {
+    json!({
+        "url": url,
+        "shards": 1,
+        "session_start_limit": {
+            "total": 1000,
+            "remaining": 1000,
+            "reset_after": 86_400_000,
+            "max_concurrency": 1
+        }
+    })
+}
+
+/// A REST handle talking to `rest` instead of Discord.
+pub fn mock_http(rest: &MockRest) -> Http {
+    let mut http = Http::new("mock-token").unwrap();
+    http.set_api_url(&rest.url);
+    http
+}
+
+/// A gateway session connecting wherever `GET /gateway/bot` on `http` points
+/// it, normally a `MockGateway`. The gateway is JSON without transport
+/// compression, so scripted payloads go out as-is.
+pub fn mock_client(http: &Http) -> DiscordClient {
+    let mut client = DiscordClient::new(http.clone());
+    client.set_zlib_stream(false);
+    client
+}
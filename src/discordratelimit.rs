@@ -0,0 +1,364 @@
+use crate::discordmessage::RateLimitResponse;
+use reqwest::header::HeaderMap;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::sync::OwnedMutexGuard;
+use tokio::time::{Duration, Instant};
+
+/// Rate limit key of a REST request: the method and path, with minor ids
+/// masked. The first channel, guild or webhook id is the major parameter and
+/// stays, since Discord limits each of those separately even within a bucket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    key: String,
+    major: String,
+}
+
+impl Route {
+    pub fn new(method: &reqwest::Method, path: &str) -> Route {
+        let mut major = None;
+        let mut prev = "";
+        let mut masked = vec![];
+        for seg in path.split('/') {
+            let is_major =
+                major.is_none() && (prev == "channels" || prev == "guilds" || prev == "webhooks");
+            masked.push(if is_major {
+                major = Some(seg.to_string());
+                seg
+            } else if prev == "reactions" {
+                ":emoji"
+            } else if !seg.is_empty() && seg.bytes().all(|b| b.is_ascii_digit()) {
+                ":id"
+            } else {
+                seg
+            });
+            prev = seg;
+        }
+        Route {
+            key: format!("{} {}", method, masked.join("/")),
+            major: major.unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct Bucket {
+    remaining: Option<u32>,
+    reset_at: Option<Instant>,
+}
+
+#[derive(Default)]
+struct Limits {
+    /// Route key to bucket key, learned from `X-RateLimit-Bucket`. Until a
+    /// route has been seen its own key doubles as the bucket key.
+    routes: HashMap<String, String>,
+    buckets: HashMap<String, Arc<tokio::sync::Mutex<Bucket>>>,
+    global_until: Option<Instant>,
+}
+
+/// Holds a bucket for the duration of one request. Requests in the same bucket
+/// queue up behind it, so each one is scheduled with the limits reported by the
+/// response before it.
+pub struct Ticket {
+    route: Route,
+    bucket: OwnedMutexGuard<Bucket>,
+}
+
+/// Tracks Discord's per-route and global REST rate limits from the
+/// `X-RateLimit-*` response headers and delays requests that would exceed them.
+/// Clones share the same state.
+#[derive(Clone, Default)]
+pub struct RateLimiter {
+    limits: Arc<Mutex<Limits>>,
+}
+
+impl RateLimiter {
+    pub fn new() -> RateLimiter {
+        RateLimiter::default()
+    }
+
+    /// Waits until a request on `route` may be sent.
+    pub async fn acquire(&self, route: &Route) -> Ticket {
+        let bucket = {
+            let mut limits = self.limits.lock().unwrap();
+            let key = match limits.routes.get(&route.key) {
+                Some(key) => key.clone(),
+                None => route.key.clone(),
+            };
+            limits.buckets.entry(key).or_default().clone()
+        };
+        let mut bucket = bucket.lock_owned().await;
+        if bucket.remaining == Some(0) {
+            if let Some(reset_at) = bucket.reset_at {
+                if reset_at > Instant::now() {
+                    println!(
+                        "Waiting {:?} for rate limit on {}",
+                        reset_at - Instant::now(),
+                        route.key
+                    );
+                }
+                tokio::time::delay_until(reset_at).await;
+            }
+            bucket.remaining = None;
+        }
+        let global_until = self.limits.lock().unwrap().global_until;
+        if let Some(until) = global_until {
+            tokio::time::delay_until(until).await;
+        }
+        Ticket {
+            route: route.clone(),
+            bucket,
+        }
+    }
+
+    /// Records the limits reported by a response and releases the bucket.
+    /// `body` is only read for a 429's `retry_after`.
+    pub fn update(
+        &self,
+        ticket: Ticket,
+        status: reqwest::StatusCode,
+        headers: &HeaderMap,
+        body: &str,
+    ) {
+        let Ticket { route, mut bucket } = ticket;
+        let now = Instant::now();
+        if let Some(remaining) = header(headers, "x-ratelimit-remaining") {
+            bucket.remaining = Some(remaining as u32);
+        }
+        if let Some(after) = header(headers, "x-ratelimit-reset-after") {
+            bucket.reset_at = Some(now + Duration::from_secs_f64(after));
+        }
+
+        let mut limits = self.limits.lock().unwrap();
+        if status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            let retry_after = retry_after(headers, body);
+            if headers.contains_key("x-ratelimit-global") {
+                limits.global_until = Some(now + retry_after);
+            } else {
+                bucket.remaining = Some(0);
+                bucket.reset_at = Some(now + retry_after);
+            }
+        }
+        if let Some(hash) = headers
+            .get("x-ratelimit-bucket")
+            .and_then(|v| v.to_str().ok())
+        {
+            let key = format!("{}:{}", hash, route.major);
+            if limits.routes.get(&route.key) != Some(&key) {
+                // retire the provisional bucket; the real one inherits its state
+                limits.buckets.remove(&route.key);
+                let state = bucket.clone();
+                limits
+                    .buckets
+                    .entry(key.clone())
+                    .or_insert_with(|| Arc::new(tokio::sync::Mutex::new(state)));
+                limits.routes.insert(route.key, key);
+            }
+        }
+    }
+}
+
+/// Gateway commands Discord accepts per connection per minute. Heartbeats
+/// count too but skip the queue, so a few are left over for them.
+pub const GATEWAY_COMMANDS_PER_MINUTE: usize = 120;
+const HEARTBEAT_ALLOWANCE: usize = 5;
+/// Presence updates accepted per connection per minute, within the above.
+pub const PRESENCE_UPDATES_PER_MINUTE: usize = 5;
+
+/// Gateway commands with a limit of their own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandKind {
+    PresenceUpdate,
+    Other,
+}
+
+/// Send times of the commands in the last minute, including ones scheduled
+/// for later.
+struct Window {
+    limit: usize,
+    sent: VecDeque<Instant>,
+}
+
+impl Window {
+    fn new(limit: usize) -> Window {
+        Window {
+            limit,
+            sent: VecDeque::new(),
+        }
+    }
+    fn earliest(&mut self, now: Instant) -> Instant {
+        let minute = Duration::from_secs(60);
+        while self.sent.front().is_some_and(|&at| at + minute <= now) {
+            self.sent.pop_front();
+        }
+        if self.sent.len() < self.limit {
+            now
+        } else {
+            self.sent[self.sent.len() - self.limit] + minute
+        }
+    }
+}
+
+/// Schedules the commands sent on one gateway connection so they stay within
+/// Discord's limits. Commands keep their order, so one held back by a
+/// stricter limit holds back those after it too.
+pub struct CommandLimiter {
+    commands: Window,
+    presences: Window,
+    last: Option<Instant>,
+}
+
+impl Default for CommandLimiter {
+    fn default() -> CommandLimiter {
+        CommandLimiter::new()
+    }
+}
+
+impl CommandLimiter {
+    pub fn new() -> CommandLimiter {
+        CommandLimiter {
+            commands: Window::new(GATEWAY_COMMANDS_PER_MINUTE - HEARTBEAT_ALLOWANCE),
+            presences: Window::new(PRESENCE_UPDATES_PER_MINUTE),
+            last: None,
+        }
+    }
+
+    /// Takes a slot for one command and returns when it may be sent.
+    pub fn reserve(&mut self, kind: CommandKind) -> Instant {
+        let now = Instant::now();
+        let mut at = std::cmp::max(self.commands.earliest(now), self.last.unwrap_or(now));
+        if kind == CommandKind::PresenceUpdate {
+            at = std::cmp::max(at, self.presences.earliest(now));
+            self.presences.sent.push_back(at);
+        }
+        self.commands.sent.push_back(at);
+        self.last = Some(at);
+        at
+    }
+}
+
+/// How long a 429 response says to wait. `Retry-After` is in milliseconds on
+/// v6 but seconds on later versions, so the wait comes from the unambiguous
+/// reset header, or the body's `retry_after` in milliseconds.
+pub fn retry_after(headers: &HeaderMap, body: &str) -> Duration {
+    header(headers, "x-ratelimit-reset-after")
+        .map(Duration::from_secs_f64)
+        .or_else(|| {
+            let limit = serde_json::from_str::<RateLimitResponse>(body).ok()?;
+            Some(Duration::from_millis(limit.retry_after.into()))
+        })
+        .unwrap_or(Duration::from_secs(1))
+}
+
+fn header(headers: &HeaderMap, name: &str) -> Option<f64> {
+    headers.get(name)?.to_str().ok()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::discordmock::*;
+    use serde_json::json;
+
+    const REACTION: &str = "/api/v6/channels/200/messages/1/reactions/%F0%9F%94%A5/@me";
+    const MESSAGE: &str = "/api/v6/channels/200/messages/1";
+
+    #[test]
+    fn routes_mask_minor_ids_but_keep_the_major_parameter() {
+        let route = Route::new(&reqwest::Method::PUT, REACTION);
+        assert_eq!(
+            route.key,
+            "PUT /api/v6/channels/200/messages/:id/reactions/:emoji/@me"
+        );
+        assert_eq!(route.major, "200");
+
+        let route = Route::new(&reqwest::Method::GET, "/api/v6/guilds/5/members/6");
+        assert_eq!(route.key, "GET /api/v6/guilds/5/members/:id");
+        assert_eq!(route.major, "5");
+
+        let route = Route::new(&reqwest::Method::POST, "/api/v6/users/@me/channels");
+        assert_eq!(route.key, "POST /api/v6/users/@me/channels");
+        assert_eq!(route.major, "");
+
+        // the same endpoint in another channel is limited separately
+        assert_ne!(
+            Route::new(&reqwest::Method::DELETE, MESSAGE),
+            Route::new(&reqwest::Method::DELETE, "/api/v6/channels/201/messages/1")
+        );
+    }
+
+    #[tokio::test]
+    async fn waits_for_reset_once_a_bucket_is_used_up() {
+        let rest = MockRest::start().await;
+        let http = mock_http(&rest);
+        let headers = [
+            ("x-ratelimit-remaining", "0"),
+            ("x-ratelimit-reset-after", "0.3"),
+        ];
+        rest.respond_with_headers("PUT", REACTION, 200, &headers, json!({}));
+        http.create_reaction("200", "1", "%F0%9F%94%A5")
+            .await
+            .unwrap();
+
+        let start = Instant::now();
+        http.create_reaction("200", "1", "%F0%9F%94%A5")
+            .await
+            .unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(250));
+        assert_eq!(rest.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn routes_reporting_the_same_bucket_share_its_limit() {
+        let rest = MockRest::start().await;
+        let http = mock_http(&rest);
+        let bucket = ("x-ratelimit-bucket", "abcd");
+        let remaining = ("x-ratelimit-remaining", "1");
+        rest.respond_with_headers("PUT", REACTION, 200, &[bucket, remaining], json!({}));
+        rest.respond_with_headers("DELETE", MESSAGE, 200, &[bucket, remaining], json!({}));
+        let headers = [
+            bucket,
+            ("x-ratelimit-remaining", "0"),
+            ("x-ratelimit-reset-after", "0.3"),
+        ];
+        rest.respond_with_headers("PUT", REACTION, 200, &headers, json!({}));
+        http.create_reaction("200", "1", "%F0%9F%94%A5")
+            .await
+            .unwrap();
+        http.delete_msg("200", "1").await.unwrap();
+        http.create_reaction("200", "1", "%F0%9F%94%A5")
+            .await
+            .unwrap();
+
+        let start = Instant::now();
+        http.delete_msg("201", "1").await.unwrap();
+        assert!(start.elapsed() < Duration::from_millis(250));
+        http.delete_msg("200", "1").await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(250));
+    }
+
+    #[tokio::test]
+    async fn global_rate_limits_hold_back_every_route() {
+        let rest = MockRest::start().await;
+        let http = mock_http(&rest);
+        // v6 puts milliseconds in Retry-After; the wait comes from the body
+        let headers = [("retry-after", "500"), ("x-ratelimit-global", "true")];
+        let body =
+            json!({ "message": "You are being rate limited.", "retry_after": 500, "global": true });
+        rest.respond_with_headers("PUT", REACTION, 429, &headers, body);
+
+        let start = Instant::now();
+        let reaction = {
+            let http = http.clone();
+            tokio::spawn(async move { http.create_reaction("200", "1", "%F0%9F%94%A5").await })
+        };
+        rest.wait_for(1).await;
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+        http.delete_msg("201", "1").await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(450));
+
+        // the rate limited request is sent again once the wait is over
+        reaction.await.unwrap().unwrap();
+        assert_eq!(rest.requests().len(), 3);
+    }
+}
@@ -0,0 +1,155 @@
+use crate::discordclient::Encoding;
+use crate::discorderror::*;
+use crate::discordmessage::DiscordMessage;
+use serde_json::json;
+use std::io::{BufRead, Write};
+use std::sync::{Arc, Mutex};
+
+/// Appends every gateway payload a client receives to a JSONL file, one line
+/// per payload:
+///
+/// `{"ts":1589000000000,"shard":[0,1],"encoding":"json","frame":"{\"op\":10,...}"}`
+///
+/// `ts` is milliseconds since the Unix epoch and `frame` the payload after
+/// decompression; ETF frames are hex encoded. Clones write to the same file.
+#[derive(Clone)]
+pub struct Recorder {
+    file: Arc<Mutex<std::fs::File>>,
+}
+
+impl Recorder {
+    pub fn create(filename: &str) -> std::io::Result<Recorder> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(filename)?;
+        Ok(Recorder {
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+    pub fn record(&self, shard: Option<(u64, u64)>, encoding: Encoding, data: &[u8]) {
+        let ts = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        let frame = match encoding {
+            Encoding::Json => String::from_utf8_lossy(data).into_owned(),
+            Encoding::Etf => data.iter().map(|b| format!("{:02x}", b)).collect(),
+        };
+        let (id, count) = shard.unwrap_or((0, 1));
+        let line = json!({
+            "ts": ts,
+            "shard": [id, count],
+            "encoding": encoding.query(),
+            "frame": frame
+        });
+        // written whole and unbuffered so a crash can't lose the payload that caused it
+        let mut file = self.file.lock().unwrap();
+        if let Err(e) = writeln!(file, "{}", line) {
+            println!("Recording gateway frame failed: {}", e);
+        }
+    }
+}
+
+/// One payload read back from a recording.
+pub struct Frame {
+    pub ts: u64,
+    pub shard: (u64, u64),
+    pub msg: DiscordResult<DiscordMessage>,
+}
+
+/// Reads a file written by `Recorder` and decodes its payloads again, in the
+/// order they were received.
+pub fn replay(filename: &str) -> std::io::Result<Vec<Frame>> {
+    let file = std::io::BufReader::new(std::fs::File::open(filename)?);
+    let mut frames = vec![];
+    for line in file.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        frames.push(parse_line(&line).unwrap_or_else(|e| Frame {
+            ts: 0,
+            shard: (0, 1),
+            msg: Err(e),
+        }));
+    }
+    Ok(frames)
+}
+
+fn parse_line(line: &str) -> DiscordResult<Frame> {
+    #[derive(serde::Deserialize)]
+    struct Line {
+        ts: u64,
+        shard: (u64, u64),
+        encoding: String,
+        frame: String,
+    }
+    let line: Line = serde_json::from_str(line)?;
+    let (encoding, data) = match line.encoding.as_str() {
+        "json" => (Encoding::Json, line.frame.into_bytes()),
+        "etf" => (Encoding::Etf, unhex(&line.frame)?),
+        other => {
+            return Err(DiscordError::Decode(format!(
+                "unknown encoding {:?} in recording",
+                other
+            )))
+        }
+    };
+    Ok(Frame {
+        ts: line.ts,
+        shard: line.shard,
+        msg: encoding.decode(&data),
+    })
+}
+
+fn unhex(s: &str) -> DiscordResult<Vec<u8>> {
+    s.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [_, _] => u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok(),
+            _ => None,
+        })
+        .collect::<Option<Vec<u8>>>()
+        .ok_or_else(|| DiscordError::Decode(format!("bad hex frame {:?}", s)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::discordetf;
+
+    #[test]
+    fn replays_what_was_recorded() {
+        let path = std::env::temp_dir().join(format!("gateway-{}.jsonl", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+
+        let hello = br#"{"op":10,"s":null,"t":null,"d":{"heartbeat_interval":41250}}"#;
+        let create = serde_json::json!({
+            "op": 0, "s": 2, "t": "GUILD_CREATE",
+            "d": { "id": "81384788765712384", "name": "x", "owner_id": "1" }
+        });
+        let recorder = Recorder::create(path).unwrap();
+        recorder.record(None, Encoding::Json, hello);
+        recorder
+            .clone()
+            .record(Some((1, 2)), Encoding::Etf, &discordetf::encode(&create));
+        recorder.record(None, Encoding::Json, b"{\"op\":0,\"d\":");
+
+        let frames = replay(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(frames.len(), 3);
+        assert!(matches!(
+            frames[0].msg,
+            Ok(DiscordMessage::Hello { ref d }) if d.heartbeat_interval == 41250
+        ));
+        assert_eq!(frames[1].shard, (1, 2));
+        assert!(matches!(
+            frames[1].msg,
+            Ok(DiscordMessage::GuildCreate { s: 2, ref d }) if d.id == "81384788765712384"
+        ));
+        assert!(frames[2].msg.is_err());
+        assert!(frames[0].ts > 0);
+    }
+}
@@ -0,0 +1,381 @@
+use crate::discordclient::*;
+use crate::discorderror::*;
+use crate::discordmessage::*;
+use crate::discordrecord;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, oneshot};
+
+/// How long Request Guild Members may take to deliver its last chunk.
+const MEMBER_REQUEST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// The shard whose session receives a guild's events, given the guild's id.
+pub fn shard_id(guild_id: &str, shard_count: u64) -> u64 {
+    match guild_id.parse::<u64>() {
+        Ok(id) if shard_count > 0 => (id >> 22) % shard_count,
+        _ => 0,
+    }
+}
+
+/// The members sent in answer to a Request Guild Members, with their
+/// presences if the bot has the GUILD_PRESENCES intent.
+#[derive(Debug, Clone, Default)]
+pub struct GuildMembers {
+    pub members: Vec<GuildMember>,
+    pub presences: Vec<PresenceUpdate>,
+    /// Requested user ids that are not members of the guild.
+    pub not_found: Vec<String>,
+}
+
+/// A Request Guild Members waiting for the rest of its chunks.
+struct MemberRequest {
+    members: GuildMembers,
+    done: oneshot::Sender<GuildMembers>,
+}
+
+/// Request Guild Members in flight on any shard, by nonce.
+#[derive(Default)]
+struct MemberRequests {
+    next_nonce: u64,
+    pending: HashMap<String, MemberRequest>,
+}
+
+impl MemberRequests {
+    fn insert(&mut self, done: oneshot::Sender<GuildMembers>) -> String {
+        self.next_nonce += 1;
+        let nonce = self.next_nonce.to_string();
+        let members = GuildMembers::default();
+        self.pending
+            .insert(nonce.clone(), MemberRequest { members, done });
+        nonce
+    }
+    /// Adds a chunk to the request it answers, finishing the request with
+    /// its last chunk. Returns chunks no request is waiting for.
+    fn collect(&mut self, chunk: GuildMembersChunk) -> Option<GuildMembersChunk> {
+        let nonce = chunk.nonce.clone().unwrap_or_default();
+        let request = match self.pending.get_mut(&nonce) {
+            Some(request) => request,
+            None => return Some(chunk),
+        };
+        request.members.members.extend(chunk.members);
+        request.members.presences.extend(chunk.presences);
+        request.members.not_found.extend(chunk.not_found);
+        if chunk.chunk_index + 1 >= chunk.chunk_count {
+            let request = self.pending.remove(&nonce).unwrap();
+            let _ = request.done.send(request.members);
+        }
+        None
+    }
+}
+
+/// Sends gateway commands to running shards from any task. Clones share the
+/// same shards.
+#[derive(Clone)]
+pub struct ShardHandle {
+    /// Command queue of each shard, by shard id. Empty when replaying.
+    shards: Vec<mpsc::UnboundedSender<GatewayCommand>>,
+    member_requests: Arc<Mutex<MemberRequests>>,
+}
+
+impl ShardHandle {
+    /// Changes the bot's status and activities on every shard, now and for
+    /// later identifies. Shards between sessions send it once they are back.
+    /// Returns the longest any shard held the update back for the gateway
+    /// rate limits.
+    pub async fn set_presence(&self, presence: Presence) -> DiscordResult<std::time::Duration> {
+        let mut replies = vec![];
+        for shard in &self.shards {
+            let (delayed, rx) = oneshot::channel();
+            let command = GatewayCommand::SetPresence {
+                presence: presence.clone(),
+                delayed,
+            };
+            // a shard that has stopped drops `delayed`, failing below
+            let _ = shard.send(command);
+            replies.push(rx);
+        }
+        let mut longest = std::time::Duration::from_secs(0);
+        for rx in replies {
+            let delay = rx
+                .await
+                .map_err(|_| DiscordError::Gateway(tungstenite::Error::ConnectionClosed))??;
+            longest = longest.max(delay);
+        }
+        Ok(longest)
+    }
+    /// Asks the shard of `guild_id` for guild members, with presences, and
+    /// waits for every GUILD_MEMBERS_CHUNK answering it. The chunks are not
+    /// passed on as events. Fails if the answer takes longer than
+    /// `MEMBER_REQUEST_TIMEOUT`, e.g. because the session was lost.
+    pub async fn request_guild_members(
+        &self,
+        guild_id: &str,
+        query: MemberQuery,
+    ) -> DiscordResult<GuildMembers> {
+        let shard = shard_id(guild_id, self.shards.len() as u64) as usize;
+        let (done, rx) = oneshot::channel();
+        let nonce = self.member_requests.lock().unwrap().insert(done);
+        let command = GatewayCommand::RequestGuildMembers {
+            guild_id: guild_id.to_string(),
+            query,
+            nonce: nonce.clone(),
+        };
+        let sent = self
+            .shards
+            .get(shard)
+            .is_some_and(|shard| shard.send(command).is_ok());
+        let members = if sent {
+            Some(tokio::time::timeout(MEMBER_REQUEST_TIMEOUT, rx).await)
+        } else {
+            None
+        };
+        // a request that timed out or was never sent is still pending
+        self.member_requests.lock().unwrap().pending.remove(&nonce);
+        match members {
+            Some(Ok(Ok(members))) => Ok(members),
+            None | Some(Ok(Err(_))) => {
+                Err(DiscordError::Gateway(tungstenite::Error::ConnectionClosed))
+            }
+            Some(Err(_)) => Err(DiscordError::Timeout {
+                what: "Request Guild Members",
+                after: MEMBER_REQUEST_TIMEOUT,
+            }),
+        }
+    }
+}
+
+/// Runs one gateway session per shard and merges their events into a single
+/// stream.
+pub struct ShardManager {
+    shard_count: u64,
+    /// Health of each running shard, by shard id.
+    health: Vec<GatewayHealth>,
+    handle: ShardHandle,
+    events: mpsc::UnboundedReceiver<DiscordResult<DiscordMessage>>,
+}
+
+impl ShardManager {
+    /// Starts as many shards as Discord recommends for this bot. `first` runs
+    /// shard 0 and the others are its siblings, sharing its settings. Shards
+    /// are identified in groups of `max_concurrency`, five seconds apart, to
+    /// stay within the session start rate limit. Nothing is started if that
+    /// would leave fewer than `SESSION_START_RESERVE` of the day's session
+    /// starts, so a crash loop can't use them all up and get the token reset.
+    pub async fn start(
+        mut first: DiscordClient,
+        identify: Identify,
+    ) -> DiscordResult<ShardManager> {
+        let gateway = first.gateway_bot().await?;
+        let shard_count = std::cmp::max(gateway.shards, 1);
+        let limit = &gateway.session_start_limit;
+        if u64::from(limit.remaining) < shard_count + u64::from(SESSION_START_RESERVE) {
+            return Err(DiscordError::SessionStartLimit {
+                remaining: limit.remaining,
+                reset_after: std::time::Duration::from_millis(limit.reset_after),
+            });
+        }
+        let concurrency = u64::from(std::cmp::max(
+            gateway.session_start_limit.max_concurrency.unwrap_or(1),
+            1,
+        ));
+        println!(
+            "Starting {} shard(s), {} at a time",
+            shard_count, concurrency
+        );
+
+        let (tx, events) = mpsc::unbounded_channel();
+        let mut clients: Vec<_> = (1..shard_count).map(|_| first.sibling()).collect();
+        clients.insert(0, first);
+        let health = clients.iter().map(DiscordClient::health).collect();
+        let handle = ShardHandle {
+            shards: clients.iter().map(DiscordClient::queue).collect(),
+            member_requests: Arc::default(),
+        };
+        for (id, mut client) in (0..shard_count).zip(clients) {
+            if id > 0 && id % concurrency == 0 {
+                tokio::time::delay_for(std::time::Duration::from_secs(5)).await;
+            }
+            client.set_identify(identify.clone().shard(id, shard_count));
+            let member_requests = handle.member_requests.clone();
+            tokio::spawn(Self::run(client, tx.clone(), member_requests));
+        }
+
+        Ok(ShardManager {
+            shard_count,
+            health,
+            handle,
+            events,
+        })
+    }
+    /// Plays back a gateway recording as if its shards were live. The stream
+    /// ends after the last recorded payload.
+    pub fn replay(filename: &str) -> std::io::Result<ShardManager> {
+        let frames = discordrecord::replay(filename)?;
+        let shard_count = frames.iter().map(|f| f.shard.1).max().unwrap_or(1);
+        let (tx, events) = mpsc::unbounded_channel();
+        for frame in frames {
+            let _ = tx.send(frame.msg);
+        }
+        Ok(ShardManager {
+            shard_count,
+            health: vec![],
+            handle: ShardHandle {
+                shards: vec![],
+                member_requests: Arc::default(),
+            },
+            events,
+        })
+    }
+    /// Passes a shard's events on, keeping back the member chunks a request
+    /// is waiting for.
+    async fn run(
+        mut client: DiscordClient,
+        tx: mpsc::UnboundedSender<DiscordResult<DiscordMessage>>,
+        member_requests: Arc<Mutex<MemberRequests>>,
+    ) {
+        loop {
+            let msg = match client.next_msg().await {
+                Ok(DiscordMessage::GuildMembersChunk { s, d }) => {
+                    match member_requests.lock().unwrap().collect(d) {
+                        Some(d) => Ok(DiscordMessage::GuildMembersChunk { s, d }),
+                        None => continue,
+                    }
+                }
+                msg => msg,
+            };
+            let fatal = matches!(msg, Err(DiscordError::GatewayClosed { .. }));
+            if tx.send(msg).is_err() || fatal {
+                return;
+            }
+        }
+    }
+    pub fn shard_count(&self) -> u64 {
+        self.shard_count
+    }
+    /// Health of each shard, by shard id. Empty when replaying.
+    pub fn health(&self) -> &[GatewayHealth] {
+        &self.health
+    }
+    /// Sends commands to the shards from other tasks. When replaying there
+    /// are no shards to send to.
+    pub fn handle(&self) -> ShardHandle {
+        self.handle.clone()
+    }
+    /// Returns the next event from any shard, or `None` once every shard has
+    /// stopped. Errors are mostly payloads a shard could not decode, and the
+    /// other events keep flowing; a shard that returns
+    /// `DiscordError::GatewayClosed` has stopped.
+    pub async fn next_msg(&mut self) -> Option<DiscordResult<DiscordMessage>> {
+        self.events.recv().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::discordmock::*;
+    use serde_json::json;
+
+    async fn start(rest: &MockRest, gateway: &mut MockGateway) -> ShardManager {
+        rest.respond("GET", "/api/v6/gateway/bot", 200, gateway_bot(&gateway.url));
+        let client = mock_client(&mock_http(rest));
+        let mut shards = ShardManager::start(client, Identify::new()).await.unwrap();
+        gateway.expect_op(2).await;
+        gateway.ready("100", &[]);
+        assert!(matches!(
+            shards.next_msg().await,
+            Some(Ok(DiscordMessage::Ready { .. }))
+        ));
+        shards
+    }
+
+    fn chunk(index: u32, members: &[&str], not_found: &[&str], nonce: &str) -> serde_json::Value {
+        let members: Vec<_> = members
+            .iter()
+            .map(|id| json!({ "user": { "id": id }, "roles": [] }))
+            .collect();
+        json!({
+            "guild_id": "50",
+            "members": members,
+            "chunk_index": index,
+            "chunk_count": 2,
+            "not_found": not_found,
+            "nonce": nonce
+        })
+    }
+
+    #[tokio::test]
+    async fn member_chunks_are_reassembled_by_nonce() {
+        let rest = MockRest::start().await;
+        let mut gateway = MockGateway::start().await;
+        let mut shards = start(&rest, &mut gateway).await;
+
+        let handle = shards.handle();
+        let members = tokio::spawn(async move {
+            let ids = vec!["1".to_string(), "2".to_string(), "3".to_string()];
+            handle
+                .request_guild_members("50", MemberQuery::UserIds(ids))
+                .await
+        });
+        let request = gateway.expect_op(8).await;
+        assert_eq!(request["d"]["user_ids"], json!(["1", "2", "3"]));
+        let nonce = request["d"]["nonce"].as_str().unwrap().to_string();
+        gateway.dispatch("GUILD_MEMBERS_CHUNK", chunk(0, &["1"], &[], &nonce));
+        gateway.dispatch("GUILD_MEMBERS_CHUNK", chunk(0, &["9"], &[], "other"));
+        gateway.dispatch("GUILD_MEMBERS_CHUNK", chunk(1, &["2"], &["3"], &nonce));
+        gateway.dispatch("TYPING_START", json!({}));
+
+        // only the chunk nobody asked for is passed on
+        match shards.next_msg().await {
+            Some(Ok(DiscordMessage::GuildMembersChunk { d, .. })) => {
+                assert_eq!(d.nonce.as_deref(), Some("other"))
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(matches!(
+            shards.next_msg().await,
+            Some(Ok(DiscordMessage::Unknown { .. }))
+        ));
+        let members = members.await.unwrap().unwrap();
+        let ids: Vec<_> = members
+            .members
+            .iter()
+            .map(|m| m.user.as_ref().unwrap().id.as_str())
+            .collect();
+        assert_eq!(ids, ["1", "2"]);
+        assert_eq!(members.not_found, ["3"]);
+    }
+
+    #[tokio::test]
+    async fn presence_goes_out_on_every_shard() {
+        let rest = MockRest::start().await;
+        let mut gateway = MockGateway::start().await;
+        let shards = start(&rest, &mut gateway).await;
+
+        let handle = shards.handle();
+        let delay = tokio::spawn(async move {
+            let presence =
+                Presence::new(Status::Idle).activity(ActivityType::Watching, "120 users");
+            handle.set_presence(presence).await
+        });
+        let update = gateway.expect_op(3).await;
+        assert_eq!(update["d"]["status"], "idle");
+        assert_eq!(
+            update["d"]["activities"],
+            json!([{ "name": "120 users", "type": 3 }])
+        );
+        // nothing else was sent this minute, so the update went straight out
+        assert_eq!(
+            delay.await.unwrap().unwrap(),
+            std::time::Duration::from_secs(0)
+        );
+    }
+
+    #[test]
+    fn guilds_map_to_shards_by_snowflake() {
+        assert_eq!(shard_id("41771983423143937", 1), 0);
+        // (41771983423143937 >> 22) % 4 == 2
+        assert_eq!(shard_id("41771983423143937", 4), 2);
+        assert_eq!(shard_id("not a snowflake", 4), 0);
+    }
+}
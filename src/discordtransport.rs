@@ -0,0 +1,109 @@
+use futures_util::future::BoxFuture;
+use futures_util::sink::Sink;
+use futures_util::stream::Stream;
+use httparse::Header;
+use std::pin::Pin;
+use tokio::sync::mpsc;
+use tungstenite::Message;
+
+/// Outgoing half of a gateway connection.
+pub type MessageSink = Pin<Box<dyn Sink<Message, Error = tungstenite::Error> + Send + Sync>>;
+/// Incoming half of a gateway connection.
+pub type MessageStream =
+    Pin<Box<dyn Stream<Item = Result<Message, tungstenite::Error>> + Send + Sync>>;
+
+/// Opens the websocket a gateway session runs over. `url` is the full gateway
+/// URL including query parameters and `auth` the Authorization header value.
+pub trait Transport: Send + Sync {
+    fn connect(
+        &self,
+        url: String,
+        auth: String,
+    ) -> BoxFuture<'static, Result<(MessageSink, MessageStream), tungstenite::Error>>;
+}
+
+/// A real websocket over async-std TCP, with native TLS for `wss://` URLs and
+/// plain TCP for `ws://` ones.
+pub struct WebSocketTransport;
+
+impl Transport for WebSocketTransport {
+    fn connect(
+        &self,
+        url: String,
+        auth: String,
+    ) -> BoxFuture<'static, Result<(MessageSink, MessageStream), tungstenite::Error>> {
+        Box::pin(async move {
+            let mut headers = [Header {
+                name: "Authorization",
+                value: auth.as_bytes(),
+            }];
+            let mut req = httparse::Request::new(&mut headers);
+            req.path = Some(&url);
+            req.method = Some("GET");
+            req.version = Some(b'1');
+
+            let (wss, _) = async_tungstenite::async_std::connect_async(req).await?;
+            let (sink, stream) = futures_util::StreamExt::split(wss);
+            Ok((
+                Box::pin(sink) as MessageSink,
+                Box::pin(stream) as MessageStream,
+            ))
+        })
+    }
+}
+
+/// The gateway's end of an in-memory connection.
+pub struct MemoryPeer {
+    pub url: String,
+    /// Messages for the client. Dropping it closes the connection.
+    pub tx: mpsc::UnboundedSender<Result<Message, tungstenite::Error>>,
+    /// Messages from the client; ends when the client drops the connection.
+    pub rx: mpsc::UnboundedReceiver<Message>,
+}
+
+/// Connects to an in-process gateway through channels, with no sockets
+/// involved. Every `connect` shows up as a `MemoryPeer` on the paired
+/// receiver.
+pub struct MemoryTransport {
+    accept: mpsc::UnboundedSender<MemoryPeer>,
+}
+
+impl MemoryTransport {
+    pub fn new() -> (MemoryTransport, mpsc::UnboundedReceiver<MemoryPeer>) {
+        let (accept, listener) = mpsc::unbounded_channel();
+        (MemoryTransport { accept }, listener)
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn connect(
+        &self,
+        url: String,
+        _auth: String,
+    ) -> BoxFuture<'static, Result<(MessageSink, MessageStream), tungstenite::Error>> {
+        let (to_client, from_gateway) = mpsc::unbounded_channel();
+        let (to_gateway, from_client) = mpsc::unbounded_channel();
+        let accepted = self.accept.send(MemoryPeer {
+            url,
+            tx: to_client,
+            rx: from_client,
+        });
+        Box::pin(async move {
+            if accepted.is_err() {
+                return Err(tungstenite::Error::ConnectionClosed);
+            }
+            let sink = futures_util::sink::unfold(
+                to_gateway,
+                |tx: mpsc::UnboundedSender<Message>, msg| async move {
+                    tx.send(msg)
+                        .map_err(|_| tungstenite::Error::ConnectionClosed)?;
+                    Ok::<_, tungstenite::Error>(tx)
+                },
+            );
+            Ok((
+                Box::pin(sink) as MessageSink,
+                Box::pin(from_gateway) as MessageStream,
+            ))
+        })
+    }
+}
@@ -0,0 +1,13 @@
+//! Discord gateway and REST client behind the whois bot in `main.rs`.
+pub mod discordclient;
+pub mod discorderror;
+pub mod discordetf;
+pub mod discordhttp;
+pub mod discordmessage;
+/// Mock Discord servers for tests; the bot's tests reach them through the `mock` feature.
+#[cfg(any(test, feature = "mock"))]
+pub mod discordmock;
+pub mod discordratelimit;
+pub mod discordrecord;
+pub mod discordshard;
+pub mod discordtransport;
@@ -1,11 +1,18 @@
 #![allow(unused_mut)]
 use serde::{Deserialize, Serialize};
 
-mod discordclient;
-mod discordmessage;
-
-use crate::discordclient::*;
-use crate::discordmessage::*;
+use discord_whois::discordclient::*;
+use discord_whois::discorderror::*;
+use discord_whois::discordhttp::{AllowedMentions, CreateMessage, Http};
+use discord_whois::discordmessage::*;
+use discord_whois::discordrecord::Recorder;
+use discord_whois::discordshard::*;
+use futures_util::future::FutureExt;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::JoinHandle;
 
 #[derive(Deserialize, Serialize, Debug)]
 struct DiscordAgentState {
@@ -28,7 +35,7 @@ impl DiscordAgentState {
             _ => Self::new(),
         }
     }
-    fn to_file_if_dirty(&mut self, filename: &str) {
+    fn flush_if_dirty(&mut self, filename: &str) {
         if self.dirty {
             self.dirty = false;
             self.to_file(filename);
@@ -38,115 +45,911 @@ impl DiscordAgentState {
         let file = std::fs::File::create(filename).unwrap();
         serde_json::ser::to_writer_pretty(std::io::BufWriter::new(file), self).unwrap();
     }
+    /// Karma of everyone as CSV, highest first.
+    fn to_csv(&self) -> String {
+        let mut karma: Vec<_> = self.userlist.iter().collect();
+        karma.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        let mut csv = "name,karma\n".to_string();
+        for (name, value) in karma {
+            if name.contains(&[',', '"', '\n', '\r'][..]) {
+                csv.push_str(&format!("\"{}\",{}\n", name.replace('"', "\"\""), value));
+            } else {
+                csv.push_str(&format!("{},{}\n", name, value));
+            }
+        }
+        csv
+    }
+}
+
+/// Stripe color of karma embeds.
+const KARMA_COLOR: u32 = 0x43b581;
+
+/// Events handled at the same time, across all channels.
+const MAX_CONCURRENT_HANDLERS: usize = 16;
+
+/// Members listed by `%whois`; the rest are only counted.
+const WHOIS_SHOWN: usize = 20;
+
+/// How long a channel's handler task waits for another message before exiting.
+const CHANNEL_IDLE: std::time::Duration = std::time::Duration::from_secs(5 * 60);
+
+/// Everything an event handler needs. Handlers run on their own tasks, each
+/// with a clone of this.
+#[derive(Clone)]
+struct Handler {
+    http: Http,
+    state: Arc<Mutex<DiscordAgentState>>,
+    permits: Arc<Semaphore>,
+    /// Health of each shard, by shard id.
+    health: Vec<GatewayHealth>,
+    shards: ShardHandle,
+    /// Where karma is saved as soon as it changes, if anywhere.
+    state_file: Option<String>,
+    /// Held while saving to `state_file`.
+    saving: Arc<Mutex<()>>,
+    channel_idle: std::time::Duration,
+    /// Errors handlers have run into, which are only printed otherwise.
+    errors: Arc<AtomicUsize>,
+}
+
+impl Handler {
+    async fn on_message(&self, msg: &Message) -> DiscordResult<()> {
+        if msg.content.starts_with("%say ") {
+            // repeating what users say mustn't let them ping @everyone through the bot
+            let reply = CreateMessage::new()
+                .content(&msg.content[5..])
+                .allowed_mentions(AllowedMentions::none());
+            self.http.create_msg(&msg.channel_id, reply).await?;
+        } else if msg.content.starts_with("++") {
+            self.add_karma(msg, msg.content[2..].to_string()).await?;
+        } else if msg.content.ends_with("++") {
+            self.add_karma(msg, msg.content[..(msg.content.len() - 2)].to_string())
+                .await?;
+        } else if msg.content.starts_with("%karma ") {
+            let name = &msg.content[7..];
+            let value = *self.state.lock().unwrap().userlist.get(name).unwrap_or(&0);
+            let embed = Embed::builder()
+                .description(&format!("{} has {} karma", name, value))
+                .color(KARMA_COLOR)
+                .build()?;
+            let reply = CreateMessage::new()
+                .embed(embed)
+                .allowed_mentions(AllowedMentions::none());
+            self.http.create_msg(&msg.channel_id, reply).await?;
+        } else if msg.content == "%export" {
+            let csv = self.state.lock().unwrap().to_csv();
+            let reply = CreateMessage::new().file("karma.csv", csv.into_bytes());
+            self.http.create_msg(&msg.channel_id, reply).await?;
+        } else if msg.content.starts_with("%whois ") {
+            self.whois(msg, &msg.content[7..]).await?;
+        } else if msg.content == "%ping" {
+            self.http
+                .create_msg(&msg.channel_id, &self.ping_reply(msg))
+                .await?;
+        } else if msg.content.starts_with("%") {
+            self.http
+                .create_reaction(&msg.channel_id, &msg.id, "%e2%9d%94")
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Lists the guild's members whose username starts with `name`, offline
+    /// ones included.
+    async fn whois(&self, msg: &Message, name: &str) -> DiscordResult<()> {
+        let guild_id = match &msg.guild_id {
+            Some(id) => id,
+            None => {
+                let reply = "%whois only works in servers";
+                self.http.create_msg(&msg.channel_id, reply).await?;
+                return Ok(());
+            }
+        };
+        let query = MemberQuery::Prefix(name.to_string());
+        let found = self.shards.request_guild_members(guild_id, query).await?;
+        let names: Vec<_> = found
+            .members
+            .iter()
+            .filter_map(|m| m.user.as_ref())
+            .map(|u| match (&u.username, &u.discriminator) {
+                (Some(name), Some(discriminator)) => format!("{}#{}", name, discriminator),
+                _ => u.id.clone(),
+            })
+            .collect();
+        let shown = names.len().min(WHOIS_SHOWN);
+        let mut reply = if names.is_empty() {
+            format!("No members named {}", name)
+        } else {
+            format!("Members named {}: {}", name, names[..shown].join(", "))
+        };
+        if names.len() > shown {
+            reply.push_str(&format!(" and {} more", names.len() - shown));
+        }
+        let reply = CreateMessage::new()
+            .content(&reply)
+            .allowed_mentions(AllowedMentions::none());
+        self.http.create_msg(&msg.channel_id, reply).await?;
+        Ok(())
+    }
+
+    /// Writes the state to `state_file` if it has changed, on a blocking
+    /// thread so the runtime's workers and the state lock aren't held up.
+    async fn save(&self) {
+        let filename = match &self.state_file {
+            Some(filename) => filename.clone(),
+            None => return,
+        };
+        let (state, saving) = (self.state.clone(), self.saving.clone());
+        let saved = tokio::task::spawn_blocking(move || {
+            // saves take turns, each writing the state as of its turn, so an
+            // older state never lands on top of a newer one
+            let _turn = saving.lock().unwrap();
+            let json = {
+                let mut state = state.lock().unwrap();
+                if !state.dirty {
+                    return Ok(());
+                }
+                state.dirty = false;
+                serde_json::to_string_pretty(&*state).unwrap()
+            };
+            std::fs::write(&filename, json)
+        })
+        .await;
+        if let Ok(Err(e)) = saved {
+            self.report(format!("Could not save state: {}", e));
+        }
+    }
+
+    /// Heartbeat latency of the shard the message came in on.
+    fn ping_reply(&self, msg: &Message) -> String {
+        let count = self.health.len() as u64;
+        let shard = msg.guild_id.as_deref().map_or(0, |id| shard_id(id, count));
+        match self.health.get(shard as usize).map(GatewayHealth::stats) {
+            Some(GatewayStats {
+                latency: Some(latency),
+                ..
+            }) => format!("Pong! Heartbeat {} ms", latency.as_millis()),
+            _ => "Pong! No heartbeat yet".to_string(),
+        }
+    }
+
+    async fn add_karma(&self, msg: &Message, user: String) -> DiscordResult<()> {
+        {
+            let mut state = self.state.lock().unwrap();
+            *state.userlist.entry(user).or_insert(0) += 1;
+            state.dirty = true;
+        }
+        self.save().await;
+        self.http
+            .create_reaction(&msg.channel_id, &msg.id, "%f0%9f%8d%80")
+            .await
+    }
+
+    async fn on_all_guilds(self, guilds: Vec<Guild>) {
+        let _permit = self.permits.acquire().await;
+        // guilds may come without their channels
+        let channels = guilds
+            .into_iter()
+            .flat_map(|g| g.channels.unwrap_or_default());
+        for c in channels {
+            if c.name.as_deref() == Some("bot-playground") {
+                if let Some(x) = &c.last_message_id {
+                    // one inaccessible channel shouldn't stop the others
+                    match self.http.get_channel_message(&c.id, x).await {
+                        Ok(lastmsg) => println!("Last Message: {:?}", lastmsg),
+                        Err(e) => self.report(format!("get_channel_message() failed: {}", e)),
+                    }
+                    if let Err(e) = self.http.create_reaction(&c.id, x, "%f0%9f%94%a5").await {
+                        self.report(format!("create_reaction() failed: {}", e));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Handles one channel's messages in the order they arrived, once
+    /// `previous`, the channel's last loop, has finished the messages it took
+    /// before going idle. Exits once the channel has been quiet for
+    /// `channel_idle`.
+    async fn channel_loop(
+        self,
+        previous: Option<JoinHandle<()>>,
+        mut rx: mpsc::UnboundedReceiver<Message>,
+    ) {
+        if let Some(previous) = previous {
+            let _ = previous.await;
+        }
+        loop {
+            let msg = match tokio::time::timeout(self.channel_idle, rx.recv()).await {
+                Ok(Some(msg)) => msg,
+                Ok(None) => return,
+                Err(_) => {
+                    // refuse new messages, but finish any that raced the timeout
+                    rx.close();
+                    continue;
+                }
+            };
+            let _permit = self.permits.acquire().await;
+            if let Err(e) = self.on_message(&msg).await {
+                self.report(format!("Error handling {:?}: {}", msg, e));
+            }
+        }
+    }
+
+    fn report(&self, error: String) {
+        println!("{}", error);
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
 }
 
 struct DiscordAgent<'a> {
-    dclient: &'a mut DiscordClient,
-    promised_guilds: usize,
+    handler: Handler,
+    my_id: String,
+    shards: &'a mut ShardManager,
+    ready_shards: HashSet<u64>,
+    promised_guilds: HashSet<String>,
     guilds: Vec<Guild>,
     exit: bool,
-    state: DiscordAgentState,
+    /// Queue and task of each channel with a running `channel_loop`.
+    channels: HashMap<String, (mpsc::UnboundedSender<Message>, JoinHandle<()>)>,
+    tasks: Vec<JoinHandle<()>>,
 }
 
 impl<'a> DiscordAgent<'a> {
-    fn new(dclient: &'a mut DiscordClient) -> Self {
+    fn new(http: Http, shards: &'a mut ShardManager) -> Self {
         Self {
-            promised_guilds: 0,
+            handler: Handler {
+                http,
+                state: Arc::new(Mutex::new(DiscordAgentState::new())),
+                permits: Arc::new(Semaphore::new(MAX_CONCURRENT_HANDLERS)),
+                health: shards.health().to_vec(),
+                shards: shards.handle(),
+                state_file: None,
+                saving: Arc::new(Mutex::new(())),
+                channel_idle: CHANNEL_IDLE,
+                errors: Arc::new(AtomicUsize::new(0)),
+            },
+            my_id: "".to_string(),
+            ready_shards: HashSet::new(),
+            promised_guilds: HashSet::new(),
             guilds: vec![],
             exit: false,
-            dclient,
-            state: DiscordAgentState::new(),
+            shards,
+            channels: HashMap::new(),
+            tasks: vec![],
         }
     }
 
-    async fn on_msg(&mut self, msg: &DiscordMessage) {
+    fn state(&self) -> std::sync::MutexGuard<'_, DiscordAgentState> {
+        self.handler.state.lock().unwrap()
+    }
+
+    /// True once every shard is ready and has delivered all of its guilds.
+    fn all_guilds_ready(&self) -> bool {
+        self.promised_guilds.is_empty()
+            && self.ready_shards.len() as u64 == self.shards.shard_count()
+    }
+
+    /// Updates the agent's view of the world and hands messages off to their
+    /// channel's handler task, so slow REST calls in one channel don't hold up
+    /// the others.
+    fn on_msg(&mut self, msg: DiscordMessage) {
         match msg {
+            DiscordMessage::Ready { d, .. } => {
+                self.my_id = d.user.id.clone();
+                // a new session replays GUILD_CREATE for each of its guilds
+                self.ready_shards.insert(d.shard.map_or(0, |s| s.0));
+                for g in &d.guilds {
+                    self.promised_guilds.insert(g.id.clone());
+                }
+                if self.all_guilds_ready() {
+                    self.on_all_guilds();
+                }
+            }
             DiscordMessage::GuildCreate { d, .. } => {
-                self.guilds.push(d.clone());
-                if self.guilds.len() == self.promised_guilds {
-                    self.on_all_guilds().await;
+                self.guilds.retain(|g| g.id != d.id);
+                let promised = self.promised_guilds.remove(&d.id);
+                self.guilds.push(d);
+                if promised && self.all_guilds_ready() {
+                    self.on_all_guilds();
                 }
             }
             DiscordMessage::MessageCreate { d: msg, .. } => {
-                if msg.author.id == self.dclient.my_id {
+                if msg.author.id == self.my_id {
                     return;
                 }
-                if msg.content.starts_with("%say ") {
-                    self.dclient
-                        .create_msg(&msg.channel_id, &msg.content[5..])
-                        .await;
-                } else if msg.content.starts_with("++") {
-                    self.add_karma(msg, msg.content[2..].to_string()).await;
-                } else if msg.content.ends_with("++") {
-                    self.add_karma(msg, msg.content[..(msg.content.len() - 2)].to_string())
-                        .await;
-                } else if msg.content.starts_with("%karma ") {
-                    let value = self
-                        .state
-                        .userlist
-                        .get(&msg.content[7..].to_string())
-                        .unwrap_or(&0);
-                    self.dclient
-                        .create_msg(&msg.channel_id, &format!("Karma: {}", value))
-                        .await;
-                } else if msg.content.starts_with("%") {
-                    self.dclient
-                        .create_reaction(&msg.channel_id, &msg.id, "%e2%9d%94")
-                        .await;
-                }
+                self.queue(msg);
             }
             _ => {}
         }
-        self.state.to_file_if_dirty("data.json");
     }
 
-    async fn add_karma(&mut self, msg: &Message, user: String) {
-        let counter = self.state.userlist.entry(user).or_insert(0);
-        *counter += 1;
-        self.state.dirty = true;
-        self.dclient
-            .create_reaction(&msg.channel_id, &msg.id, "%f0%9f%8d%80")
-            .await;
+    /// Hands a message to its channel's loop, starting one if the channel has
+    /// none or its loop has just gone idle.
+    fn queue(&mut self, msg: Message) {
+        let msg = match self.channels.get(&msg.channel_id) {
+            Some((queue, _)) => match queue.send(msg) {
+                Ok(()) => return,
+                Err(mpsc::error::SendError(msg)) => msg,
+            },
+            None => msg,
+        };
+        let channel_id = msg.channel_id.clone();
+        // an idle loop may still be handling messages that raced its timeout
+        let previous = self.channels.remove(&channel_id).map(|(_, task)| task);
+        let (tx, rx) = mpsc::unbounded_channel();
+        let task = tokio::spawn(self.handler.clone().channel_loop(previous, rx));
+        let _ = tx.send(msg);
+        self.channels.insert(channel_id, (tx, task));
     }
 
-    async fn on_all_guilds(&mut self) {
-        for g in &self.guilds {
-            for c in g.channels.as_ref().unwrap() {
-                if c.name.as_ref().unwrap() == "bot-playground" {
-                    for x in &c.last_message_id {
-                        let lastmsg = self.dclient.get_channel_message(&c.id, x).await;
-                        println!("Last Message: {:?}", lastmsg);
-                        self.dclient.create_reaction(&c.id, x, "%f0%9f%94%a5").await;
-                    }
-                }
+    /// Forgets handler tasks that have finished, including the loops of
+    /// channels that went idle.
+    fn prune(&mut self) {
+        self.channels
+            .retain(|_, (_, task)| task.now_or_never().is_none());
+        self.tasks.retain_mut(|task| task.now_or_never().is_none());
+    }
+
+    fn on_all_guilds(&mut self) {
+        let task = self.handler.clone().on_all_guilds(self.guilds.clone());
+        self.tasks.push(tokio::spawn(task));
+    }
+
+    /// Waits for the next event from any shard and handles it. Returns false
+    /// once there are no more events, or the error that stopped a shard.
+    async fn step(&mut self) -> DiscordResult<bool> {
+        match self.shards.next_msg().await {
+            Some(Ok(msg)) => {
+                self.prune();
+                self.on_msg(msg);
             }
+            Some(Err(e @ DiscordError::GatewayClosed { .. })) => return Err(e),
+            Some(Err(e)) => println!("Dropping gateway payload: {}", e),
+            None => return Ok(false),
         }
+        Ok(true)
     }
 
-    async fn main_loop(&mut self) {
+    /// Waits for every handler task to finish the events queued so far.
+    async fn finish(&mut self) {
+        // dropping the queues lets each channel loop exit once it is done
+        let loops: Vec<_> = self.channels.drain().map(|(_, (_, task))| task).collect();
+        for task in loops.into_iter().chain(self.tasks.drain(..)) {
+            let _ = task.await;
+        }
+    }
+
+    /// Handles events until told to exit, the shards stop, or a shard is
+    /// closed for good. The state is saved to `state_file` whenever it changes.
+    async fn main_loop(&mut self, state_file: Option<&str>) -> DiscordResult<()> {
+        self.handler.state_file = state_file.map(str::to_string);
+        let mut result = Ok(());
         while !self.exit {
-            let msg = self.dclient.next_msg().await;
-            self.on_msg(&msg).await;
+            match self.step().await {
+                Ok(true) => {}
+                Ok(false) => {
+                    println!("All shards stopped");
+                    break;
+                }
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        self.finish().await;
+        if let Some(filename) = state_file {
+            self.state().flush_if_dirty(filename);
         }
+        result
     }
 }
 
+/// Runs the agent over a gateway recording made with DISCORD_RECORD, without
+/// talking to Discord. State starts empty and is not saved.
+async fn replay(filename: &str) -> DiscordResult<()> {
+    let mut http = Http::new("replay")?;
+    http.set_offline(true);
+    let mut shards = ShardManager::replay(filename)
+        .unwrap_or_else(|e| panic!("Could not read recording {}: {}", filename, e));
+    let mut agent = DiscordAgent::new(http, &mut shards);
+    agent.main_loop(None).await
+}
+
 #[tokio::main]
 async fn main() {
+    // main returning Err would print its Debug form rather than the message
+    if let Err(e) = run().await {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn run() -> DiscordResult<()> {
+    if let Ok(filename) = std::env::var("DISCORD_REPLAY") {
+        return replay(&filename).await;
+    }
     let raw_tok = std::env::var("DISCORD_TOKEN")
         .expect("Expected bot token in DISCORD_TOKEN environment variable");
-    let mut dclient = DiscordClient::new(raw_tok).await;
-    dclient.get_hello().await;
-    // let ready = match std::env::var("DISCORD_SESSION") {
-    //     Ok(val) => dclient.resume(val).await,
-    //     Err(_) => dclient.identify().await,
-    // };
-    let ready = dclient.identify().await;
-
-    let mut agent = DiscordAgent::new(&mut dclient);
-    agent.promised_guilds = ready.guilds.len();
-    agent.state = DiscordAgentState::from_file("data.json");
-    agent.main_loop().await;
+    let http = Http::new(&raw_tok)?;
+    let mut dclient = DiscordClient::new(http.clone());
+    if let Ok(filename) = std::env::var("DISCORD_RECORD") {
+        let recorder = Recorder::create(&filename)
+            .unwrap_or_else(|e| panic!("Could not open recording {}: {}", filename, e));
+        dclient.set_recorder(recorder);
+    }
+    let identify = Identify::new()
+        .intents(
+            Intents::GUILDS
+                | Intents::GUILD_MEMBERS
+                | Intents::GUILD_PRESENCES
+                | Intents::GUILD_MESSAGES
+                | Intents::DIRECT_MESSAGES,
+        )
+        .presence(Presence::new(Status::Online).activity(ActivityType::Listening, "%karma"));
+    let mut shards = ShardManager::start(dclient, identify).await?;
+
+    let mut agent = DiscordAgent::new(http, &mut shards);
+    *agent.state() = DiscordAgentState::from_file("data.json");
+    agent.main_loop(Some("data.json")).await?;
 
     println!("Terminating successfully");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use discord_whois::discordmock::*;
+    use serde_json::json;
+
+    const BOT_ID: &str = "100";
+    const CHANNEL: &str = "200";
+    const USER: &str = "300";
+
+    struct Harness {
+        rest: MockRest,
+        gateway: MockGateway,
+        http: Http,
+        shards: ShardManager,
+    }
+
+    /// Connects a single shard to the mocks and readies it.
+    async fn harness() -> Harness {
+        let rest = MockRest::start().await;
+        let mut gateway = MockGateway::start().await;
+        rest.respond("GET", "/api/v6/gateway/bot", 200, gateway_bot(&gateway.url));
+        let http = mock_http(&rest);
+        let shards = ShardManager::start(mock_client(&http), Identify::new())
+            .await
+            .unwrap();
+        gateway.expect_op(2).await;
+        gateway.ready(BOT_ID, &[]);
+        Harness {
+            rest,
+            gateway,
+            http,
+            shards,
+        }
+    }
+
+    /// REST calls made after fetching the gateway.
+    fn calls(rest: &MockRest) -> Vec<(String, String, serde_json::Value)> {
+        rest.requests()
+            .into_iter()
+            .skip(1)
+            .map(|r| (r.method.clone(), r.path.clone(), r.json()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn guilds_without_channels_are_skipped_at_startup() {
+        let rest = MockRest::start().await;
+        let mut gateway = MockGateway::start().await;
+        rest.respond("GET", "/api/v6/gateway/bot", 200, gateway_bot(&gateway.url));
+        let http = mock_http(&rest);
+        let mut shards = ShardManager::start(mock_client(&http), Identify::new())
+            .await
+            .unwrap();
+        gateway.expect_op(2).await;
+        gateway.ready(BOT_ID, &["1", "2"]);
+        let guild = |id: &str| json!({ "id": id, "name": "guild", "owner_id": USER });
+        gateway.dispatch("GUILD_CREATE", guild("1"));
+        let mut with_channels = guild("2");
+        with_channels["channels"] = json!([
+            { "id": "300", "type": 2 },
+            { "id": "301", "type": 0, "name": "bot-playground", "last_message_id": "77" }
+        ]);
+        gateway.dispatch("GUILD_CREATE", with_channels);
+
+        let mut agent = DiscordAgent::new(http, &mut shards);
+        for _ in 0..3 {
+            agent.step().await.unwrap();
+        }
+        agent.finish().await;
+        let paths: Vec<_> = calls(&rest).into_iter().map(|c| c.1).collect();
+        assert_eq!(
+            paths,
+            [
+                "/api/v6/channels/301/messages/77",
+                "/api/v6/channels/301/messages/77/reactions/%f0%9f%94%a5/@me"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn say_repeats_the_message() {
+        let mut h = harness().await;
+        let mut agent = DiscordAgent::new(h.http.clone(), &mut h.shards);
+        agent.step().await.unwrap();
+        assert_eq!(agent.my_id, BOT_ID);
+
+        h.gateway.message_create(CHANNEL, USER, "%say hello there");
+        agent.step().await.unwrap();
+        agent.finish().await;
+        assert_eq!(
+            calls(&h.rest),
+            vec![(
+                "POST".to_string(),
+                "/api/v6/channels/200/messages".to_string(),
+                json!({
+                    "content": "hello there",
+                    "allowed_mentions": {
+                        "parse": [],
+                        "users": [],
+                        "roles": [],
+                        "replied_user": false
+                    }
+                })
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn karma_is_counted_and_reported() {
+        let mut h = harness().await;
+        let mut agent = DiscordAgent::new(h.http.clone(), &mut h.shards);
+        agent.step().await.unwrap();
+
+        h.gateway.message_create(CHANNEL, USER, "rust++");
+        agent.step().await.unwrap();
+        h.gateway.message_create(CHANNEL, USER, "++rust");
+        agent.step().await.unwrap();
+        h.gateway.message_create(CHANNEL, USER, "%karma rust");
+        agent.step().await.unwrap();
+        agent.finish().await;
+        assert_eq!(agent.state().userlist.get("rust"), Some(&2));
+        assert!(agent.state().dirty);
+        let calls = calls(&h.rest);
+        assert_eq!(calls.len(), 3);
+        assert_eq!(calls[0].0, "PUT");
+        assert_eq!(
+            calls[0].1,
+            "/api/v6/channels/200/messages/1002/reactions/%f0%9f%8d%80/@me"
+        );
+        assert_eq!(
+            calls[2].2["embed"],
+            json!({ "description": "rust has 2 karma", "color": 0x43b581 }),
+            "unexpected reply to %karma"
+        );
+    }
+
+    #[tokio::test]
+    async fn karma_is_saved_as_soon_as_it_changes() {
+        let mut h = harness().await;
+        let path = std::env::temp_dir().join(format!("karma-{}.json", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let mut agent = DiscordAgent::new(h.http.clone(), &mut h.shards);
+        agent.handler.state_file = Some(path.clone());
+        agent.step().await.unwrap();
+
+        h.gateway.message_create(CHANNEL, USER, "rust++");
+        agent.step().await.unwrap();
+        // the reaction goes out after saving, without waiting for another event
+        h.rest.wait_for(2).await;
+        let saved = DiscordAgentState::from_file(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(saved.userlist.get("rust"), Some(&1));
+        agent.finish().await;
+    }
+
+    #[tokio::test]
+    async fn idle_channel_loops_exit_and_are_forgotten() {
+        let mut h = harness().await;
+        let mut agent = DiscordAgent::new(h.http.clone(), &mut h.shards);
+        agent.handler.channel_idle = std::time::Duration::from_millis(100);
+        agent.step().await.unwrap();
+
+        h.gateway.message_create(CHANNEL, USER, "%say one");
+        agent.step().await.unwrap();
+        h.rest.wait_for(2).await;
+        tokio::time::delay_for(std::time::Duration::from_millis(300)).await;
+        h.gateway.message_create("201", USER, "%say two");
+        agent.step().await.unwrap();
+        assert!(!agent.channels.contains_key(CHANNEL));
+        assert!(agent.channels.contains_key("201"));
+        assert!(agent.tasks.is_empty(), "finished tasks are kept");
+
+        h.gateway.message_create(CHANNEL, USER, "%say three");
+        agent.step().await.unwrap();
+        agent.finish().await;
+        let mut sent: Vec<_> = calls(&h.rest)
+            .into_iter()
+            .map(|c| c.2["content"].clone())
+            .collect();
+        sent.sort_by_key(|c| c.to_string());
+        assert_eq!(sent, ["one", "three", "two"]);
+    }
+
+    #[tokio::test]
+    async fn new_channel_loops_wait_for_the_idle_one() {
+        let mut h = harness().await;
+        let mut agent = DiscordAgent::new(h.http.clone(), &mut h.shards);
+        agent.step().await.unwrap();
+        // a loop that timed out and closed its queue, but is still busy with
+        // a message that raced the timeout
+        let (closed, _) = mpsc::unbounded_channel();
+        let busy = std::time::Duration::from_millis(300);
+        let task = tokio::spawn(tokio::time::delay_for(busy));
+        agent.channels.insert(CHANNEL.to_string(), (closed, task));
+
+        let start = tokio::time::Instant::now();
+        h.gateway.message_create(CHANNEL, USER, "%say next");
+        agent.step().await.unwrap();
+        h.rest.wait_for(2).await;
+        assert!(start.elapsed() >= busy);
+        agent.finish().await;
+    }
+
+    #[tokio::test]
+    async fn http_clones_call_while_the_gateway_streams() {
+        let mut h = harness().await;
+        let (a, b) = (h.http.clone(), h.http.clone());
+        let (first, second, ready) = tokio::join!(
+            tokio::spawn(async move { a.create_msg(CHANNEL, "one").await }),
+            tokio::spawn(async move { b.create_msg(CHANNEL, "two").await }),
+            h.shards.next_msg()
+        );
+        first.unwrap().unwrap();
+        second.unwrap().unwrap();
+        assert!(matches!(ready, Some(Ok(DiscordMessage::Ready { .. }))));
+        assert_eq!(calls(&h.rest).len(), 2);
+    }
+
+    #[tokio::test]
+    async fn own_messages_are_ignored() {
+        let mut h = harness().await;
+        let mut agent = DiscordAgent::new(h.http.clone(), &mut h.shards);
+        agent.step().await.unwrap();
+
+        h.gateway.message_create(CHANNEL, BOT_ID, "%say loop");
+        agent.step().await.unwrap();
+        agent.finish().await;
+        assert!(calls(&h.rest).is_empty());
+    }
+
+    #[tokio::test]
+    async fn unknown_commands_get_a_reaction() {
+        let mut h = harness().await;
+        let mut agent = DiscordAgent::new(h.http.clone(), &mut h.shards);
+        agent.step().await.unwrap();
+
+        h.gateway.message_create(CHANNEL, USER, "%frobnicate");
+        agent.step().await.unwrap();
+        agent.finish().await;
+        let calls = calls(&h.rest);
+        assert_eq!(calls.len(), 1);
+        assert!(calls[0].1.ends_with("/reactions/%e2%9d%94/@me"));
+    }
+
+    #[tokio::test]
+    async fn export_attaches_karma_as_csv() {
+        let mut h = harness().await;
+        let mut agent = DiscordAgent::new(h.http.clone(), &mut h.shards);
+        agent.step().await.unwrap();
+        agent.state().userlist.insert("rust".to_string(), 2);
+        agent.state().userlist.insert("c, c++".to_string(), 3);
+
+        h.gateway.message_create(CHANNEL, USER, "%export");
+        agent.step().await.unwrap();
+        agent.finish().await;
+        let requests = h.rest.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[1].headers["content-type"].starts_with("multipart/form-data"));
+        let body = &requests[1].body;
+        assert!(body.contains("filename=\"karma.csv\""), "{}", body);
+        assert!(
+            body.contains("name,karma\n\"c, c++\",3\nrust,2\n"),
+            "{}",
+            body
+        );
+    }
+
+    #[tokio::test]
+    async fn replays_a_recorded_session_offline() {
+        let rest = MockRest::start().await;
+        let mut gateway = MockGateway::start().await;
+        rest.respond("GET", "/api/v6/gateway/bot", 200, gateway_bot(&gateway.url));
+        let path = std::env::temp_dir().join(format!("session-{}.jsonl", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let _ = std::fs::remove_file(&path);
+
+        // record a live session through the mock gateway
+        let mut live = mock_client(&mock_http(&rest));
+        live.set_recorder(Recorder::create(&path).unwrap());
+        let mut shards = ShardManager::start(live, Identify::new()).await.unwrap();
+        gateway.expect_op(2).await;
+        gateway.ready(BOT_ID, &[]);
+        gateway.message_create(CHANNEL, USER, "rust++");
+        gateway.message_create(CHANNEL, USER, "%karma rust");
+        for _ in 0..3 {
+            shards.next_msg().await.unwrap().unwrap();
+        }
+        let live_calls = rest.requests().len();
+
+        let mut http = mock_http(&rest);
+        http.set_offline(true);
+        let mut shards = ShardManager::replay(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let mut agent = DiscordAgent::new(http, &mut shards);
+        agent.main_loop(None).await.unwrap();
+        assert_eq!(agent.my_id, BOT_ID);
+        assert_eq!(agent.state().userlist.get("rust"), Some(&1));
+        assert_eq!(agent.handler.errors.load(Ordering::Relaxed), 0);
+        assert_eq!(
+            rest.requests().len(),
+            live_calls,
+            "replay reached the REST API"
+        );
+    }
+
+    #[tokio::test]
+    async fn busy_channels_do_not_hold_up_others() {
+        let mut h = harness().await;
+        h.rest.delay(
+            "POST",
+            "/api/v6/channels/200/messages",
+            std::time::Duration::from_millis(500),
+        );
+        let mut agent = DiscordAgent::new(h.http.clone(), &mut h.shards);
+        agent.step().await.unwrap();
+
+        h.gateway.message_create(CHANNEL, USER, "%say one");
+        h.gateway.message_create(CHANNEL, USER, "%say two");
+        h.gateway.message_create("201", USER, "%say three");
+        for _ in 0..3 {
+            agent.step().await.unwrap();
+        }
+        // "two" waits for "one" to be answered, "three" doesn't
+        h.rest.wait_for(3).await;
+        let sent: Vec<_> = calls(&h.rest)
+            .into_iter()
+            .map(|c| c.2["content"].clone())
+            .collect();
+        assert_eq!(sent, ["one", "three"]);
+        agent.finish().await;
+        assert_eq!(calls(&h.rest)[2].2["content"], "two");
+    }
+
+    #[tokio::test]
+    async fn fatal_close_codes_stop_the_agent() {
+        let mut h = harness().await;
+        let mut agent = DiscordAgent::new(h.http.clone(), &mut h.shards);
+        agent.step().await.unwrap();
+
+        h.gateway.close(4014, "Disallowed intent(s).");
+        match agent.main_loop(None).await {
+            Err(DiscordError::GatewayClosed { reason, message }) => {
+                assert_eq!(reason, CloseReason::DisallowedIntents);
+                assert_eq!(message, "Disallowed intent(s).");
+            }
+            other => panic!("expected a fatal close, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn rest_errors_do_not_stop_the_agent() {
+        let mut h = harness().await;
+        h.rest.respond(
+            "POST",
+            "/api/v6/channels/200/messages",
+            403,
+            json!({ "code": 50013, "message": "Missing Permissions" }),
+        );
+        let mut agent = DiscordAgent::new(h.http.clone(), &mut h.shards);
+        agent.step().await.unwrap();
+
+        h.gateway.message_create(CHANNEL, USER, "%say first");
+        agent.step().await.unwrap();
+        h.gateway.message_create(CHANNEL, USER, "%say second");
+        agent.step().await.unwrap();
+        agent.finish().await;
+        let calls = calls(&h.rest);
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[1].2["content"], "second");
+    }
+
+    #[tokio::test]
+    async fn ping_answers_before_the_first_heartbeat() {
+        let mut h = harness().await;
+        let mut agent = DiscordAgent::new(h.http.clone(), &mut h.shards);
+        agent.step().await.unwrap();
+        h.gateway.message_create(CHANNEL, USER, "%ping");
+        agent.step().await.unwrap();
+        agent.finish().await;
+        assert_eq!(
+            calls(&h.rest),
+            [(
+                "POST".to_string(),
+                "/api/v6/channels/200/messages".to_string(),
+                json!({ "content": "Pong! No heartbeat yet" })
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn whois_asks_the_gateway_for_members() {
+        let mut h = harness().await;
+        let mut agent = DiscordAgent::new(h.http.clone(), &mut h.shards);
+        agent.step().await.unwrap();
+
+        h.gateway.dispatch(
+            "MESSAGE_CREATE",
+            json!({
+                "id": "1002",
+                "channel_id": CHANNEL,
+                "guild_id": "50",
+                "author": { "id": USER, "username": "user" },
+                "content": "%whois ra",
+                "timestamp": "2020-01-01T00:00:00+00:00",
+                "edited_timestamp": null,
+                "tts": false
+            }),
+        );
+        agent.step().await.unwrap();
+        let request = h.gateway.expect_op(8).await;
+        assert_eq!(request["d"]["guild_id"], "50");
+        assert_eq!(request["d"]["query"], "ra");
+        let user = |id: &str, name: &str| json!({ "user": { "id": id, "username": name, "discriminator": "0001" }, "roles": [] });
+        h.gateway.dispatch(
+            "GUILD_MEMBERS_CHUNK",
+            json!({
+                "guild_id": "50",
+                "members": [user("1", "ras"), user("2", "rachel")],
+                "chunk_index": 0,
+                "chunk_count": 1,
+                "nonce": request["d"]["nonce"]
+            }),
+        );
+        agent.finish().await;
+        assert_eq!(
+            calls(&h.rest)[0].2["content"],
+            "Members named ra: ras#0001, rachel#0001"
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_to_start_when_session_starts_run_low() {
+        let rest = MockRest::start().await;
+        let gateway = MockGateway::start().await;
+        let mut body = gateway_bot(&gateway.url);
+        body["session_start_limit"]["remaining"] = json!(5);
+        body["session_start_limit"]["reset_after"] = json!(60_000);
+        rest.respond("GET", "/api/v6/gateway/bot", 200, body);
+
+        let client = mock_client(&mock_http(&rest));
+        match ShardManager::start(client, Identify::new()).await {
+            Err(DiscordError::SessionStartLimit {
+                remaining,
+                reset_after,
+            }) => {
+                assert_eq!(remaining, 5);
+                assert_eq!(reset_after, std::time::Duration::from_secs(60));
+            }
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("started with 5 session starts left"),
+        }
+        assert_eq!(rest.requests().len(), 1);
+    }
 }